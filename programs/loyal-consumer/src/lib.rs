@@ -0,0 +1,239 @@
+// `#[program]` still expands to the deprecated `AccountInfo::realloc`
+#![allow(deprecated)]
+
+use anchor_lang::prelude::*;
+use anchor_lang::Discriminator;
+use loyal_oracle::cpi::accounts::{CreateChat, CreateContext};
+use loyal_oracle::program::LoyalOracle;
+use loyal_oracle::{
+    Callback, CallbackAccount, ContextAccount, CALLBACK_AUTHORITY_SEED, CHAT_SEED, CONFIG_SEED,
+};
+
+declare_id!("4vExwhyHCHQ5mJ3AqNXXrckkTMBJVabjpkLMerMSLdTa");
+
+/// Seed of the PDA that owns this program's Loyal context and chats.
+pub const REQUESTER_SEED: &[u8] = b"requester";
+
+#[error_code]
+pub enum ConsumerError {
+    #[msg("Result is for a chat this program did not request.")]
+    UnknownChat,
+}
+
+#[event]
+pub struct ResultReceived {
+    pub chat: Pubkey,
+    pub chat_id: u64,
+    pub result_hash: [u8; 32],
+}
+
+/// Example consumer: a program-owned PDA requests inference from
+/// `loyal_oracle` and receives the result through the finalize callback.
+#[program]
+pub mod loyal_consumer {
+    use super::*;
+
+    pub fn initialize(ctx: Context<Initialize>) -> Result<()> {
+        let r = &mut ctx.accounts.requester;
+        r.bump = ctx.bumps.requester;
+        r.last_chat = Pubkey::default();
+        r.last_result_hash = [0u8; 32];
+        Ok(())
+    }
+
+    pub fn open_context(ctx: Context<OpenContext>) -> Result<()> {
+        let bump = ctx.accounts.requester.bump;
+        let signer: &[&[&[u8]]] = &[&[REQUESTER_SEED, &[bump]]];
+
+        loyal_oracle::cpi::create_context(CpiContext::new_with_signer(
+            ctx.accounts.loyal_program.to_account_info(),
+            CreateContext {
+                payer: ctx.accounts.payer.to_account_info(),
+                owner: ctx.accounts.requester.to_account_info(),
+                context_account: ctx.accounts.context_account.to_account_info(),
+                system_program: ctx.accounts.system_program.to_account_info(),
+            },
+            signer,
+        ))
+    }
+
+    pub fn request_inference(
+        ctx: Context<RequestInference>,
+        chat_id: u64,
+        cmk: Pubkey,
+        tx_id: Pubkey,
+        model_id: u32,
+    ) -> Result<()> {
+        let bump = ctx.accounts.requester.bump;
+        let signer: &[&[&[u8]]] = &[&[REQUESTER_SEED, &[bump]]];
+
+        // route the result back into `on_result`, with the requester writable
+        let callback = Callback {
+            program_id: crate::ID,
+            discriminator: instruction::OnResult::DISCRIMINATOR
+                .try_into()
+                .expect("anchor discriminators are 8 bytes"),
+            accounts: vec![CallbackAccount {
+                pubkey: ctx.accounts.requester.key(),
+                is_writable: true,
+            }],
+        };
+
+        loyal_oracle::cpi::create_chat(
+            CpiContext::new_with_signer(
+                ctx.accounts.loyal_program.to_account_info(),
+                CreateChat {
+                    payer: ctx.accounts.payer.to_account_info(),
+                    owner: ctx.accounts.requester.to_account_info(),
+                    context_account: ctx.accounts.context_account.to_account_info(),
+                    chat: ctx.accounts.chat.to_account_info(),
+                    model: ctx.accounts.model.to_account_info(),
+                    config: ctx.accounts.config.to_account_info(),
+                    system_program: ctx.accounts.system_program.to_account_info(),
+                },
+                signer,
+            ),
+            chat_id,
+            Some(cmk),
+            tx_id,
+            Some(callback),
+            model_id,
+            None,
+            None,
+        )
+    }
+
+    /// Invoked by `loyal_oracle::finalize_chat`; the argument layout matches the
+    /// `chat || chat_id || result_hash` payload of the callback. Any chat of the
+    /// requester's context is accepted, so several requests can be in flight.
+    pub fn on_result(
+        ctx: Context<OnResult>,
+        chat: Pubkey,
+        chat_id: u64,
+        result_hash: [u8; 32],
+    ) -> Result<()> {
+        let r = &mut ctx.accounts.requester;
+        let (context, _) = Pubkey::find_program_address(
+            &[ContextAccount::seed(), r.key().as_ref()],
+            &loyal_oracle::ID,
+        );
+        let (expected, _) = Pubkey::find_program_address(
+            &[CHAT_SEED, context.as_ref(), &chat_id.to_le_bytes()],
+            &loyal_oracle::ID,
+        );
+        require_keys_eq!(chat, expected, ConsumerError::UnknownChat);
+        r.last_chat = chat;
+        r.last_result_hash = result_hash;
+
+        emit!(ResultReceived {
+            chat,
+            chat_id,
+            result_hash,
+        });
+        Ok(())
+    }
+}
+
+/// Contexts
+
+#[derive(Accounts)]
+pub struct Initialize<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + Requester::INIT_SPACE,
+        seeds = [REQUESTER_SEED],
+        bump
+    )]
+    pub requester: Account<'info, Requester>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct OpenContext<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    #[account(seeds = [REQUESTER_SEED], bump = requester.bump)]
+    pub requester: Account<'info, Requester>,
+
+    /// CHECK: initialized by loyal_oracle, seeds checked there
+    #[account(
+        mut,
+        seeds = [ContextAccount::seed(), requester.key().as_ref()],
+        bump,
+        seeds::program = loyal_program.key()
+    )]
+    pub context_account: UncheckedAccount<'info>,
+
+    pub loyal_program: Program<'info, LoyalOracle>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(chat_id: u64)]
+pub struct RequestInference<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    #[account(seeds = [REQUESTER_SEED], bump = requester.bump)]
+    pub requester: Account<'info, Requester>,
+
+    /// CHECK: owned and validated by loyal_oracle
+    #[account(
+        mut,
+        seeds = [ContextAccount::seed(), requester.key().as_ref()],
+        bump,
+        seeds::program = loyal_program.key()
+    )]
+    pub context_account: UncheckedAccount<'info>,
+
+    /// CHECK: initialized by loyal_oracle
+    #[account(
+        mut,
+        seeds = [CHAT_SEED, context_account.key().as_ref(), &chat_id.to_le_bytes()],
+        bump,
+        seeds::program = loyal_program.key()
+    )]
+    pub chat: UncheckedAccount<'info>,
+
+    /// CHECK: registry entry, validated by loyal_oracle
+    pub model: UncheckedAccount<'info>,
+
+    /// CHECK: pause flags and oracle registry, checked by loyal_oracle
+    #[account(mut, seeds = [CONFIG_SEED], bump, seeds::program = loyal_program.key())]
+    pub config: UncheckedAccount<'info>,
+
+    pub loyal_program: Program<'info, LoyalOracle>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct OnResult<'info> {
+    /// Only loyal_oracle can sign for its callback authority PDA.
+    #[account(
+        seeds = [CALLBACK_AUTHORITY_SEED],
+        bump,
+        seeds::program = loyal_oracle::ID
+    )]
+    pub callback_authority: Signer<'info>,
+
+    #[account(mut, seeds = [REQUESTER_SEED], bump = requester.bump)]
+    pub requester: Account<'info, Requester>,
+}
+
+/// --------------------------------------------------
+/// Accounts
+/// --------------------------------------------------
+
+#[account]
+#[derive(InitSpace)]
+pub struct Requester {
+    pub bump: u8,
+    pub last_chat: Pubkey, // chat of the last result received
+    pub last_result_hash: [u8; 32],
+}
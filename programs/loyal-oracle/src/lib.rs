@@ -1,4 +1,13 @@
+// `#[program]` still expands to the deprecated `AccountInfo::realloc`
+#![allow(deprecated)]
+// instruction arguments stay flat so clients can pass them positionally
+#![allow(clippy::too_many_arguments)]
+
 use anchor_lang::prelude::*;
+use anchor_lang::solana_program::instruction::{AccountMeta, Instruction};
+use anchor_lang::solana_program::program::invoke_signed;
+use anchor_lang::system_program;
+use anchor_spl::token_interface::{self, Mint, TokenAccount, TokenInterface, TransferChecked};
 use ephemeral_rollups_sdk::anchor::{delegate, ephemeral, commit};
 use ephemeral_rollups_sdk::cpi::DelegateConfig;
 use hkdf::Hkdf;
@@ -12,8 +21,67 @@ pub const STATUS_WAITING_FOR_DELEGATION: u8 = 0;
 pub const STATUS_PENDING: u8 = 1;
 pub const STATUS_DONE:    u8 = 2;
 pub const STATUS_ERROR:   u8 = 3;
+pub const STATUS_CANCELLED: u8 = 4;
+pub const STATUS_RUNNING: u8 = 5;
+pub const STATUS_SUBMITTED: u8 = 6; // result in, challenge window open
 pub const CHAT_SEED: &[u8] = b"chat";
 pub const DEPOSIT_PDA_SEED: &[u8] = b"deposit";
+pub const CHAT_KEY_SEED: &[u8] = b"chat_key";
+pub const CHAT_ACCESS_SEED: &[u8] = b"chat_access";
+pub const ACCESS_GRANT_SEED: &[u8] = b"access_grant";
+pub const MESSAGE_SEED: &[u8] = b"message";
+/// Signs result callbacks; consumers check this PDA to authenticate the caller.
+pub const CALLBACK_AUTHORITY_SEED: &[u8] = b"callback_authority";
+pub const CALLBACK_MAX_ACCOUNTS: usize = 8;
+pub const CONFIG_SEED: &[u8] = b"config";
+pub const MODEL_SEED: &[u8] = b"model";
+pub const MODEL_NAME_MAX_LEN: usize = 32;
+pub const TOKEN_VAULT_SEED: &[u8] = b"token_vault";
+pub const TOKEN_TREASURY_SEED: &[u8] = b"token_treasury";
+pub const TOKEN_AUTHORITY_SEED: &[u8] = b"token_authority";
+pub const ALLOWED_MINTS_MAX: usize = 8;
+pub const ORACLES_MAX: usize = 16;
+pub const ORACLE_TIMEOUT_SECS: i64 = 900; // default Config::deadline_secs
+pub const ORACLE_BOND_SEED: &[u8] = b"oracle_bond";
+pub const DISPUTE_SEED: &[u8] = b"dispute";
+pub const SUBMISSIONS_SEED: &[u8] = b"submissions";
+
+// Account layout versions. Layouts are append-only, so every older layout is a
+// prefix of the current one and migrating is a zero-filled realloc plus a re-stamp.
+// Chats and contexts on an older layout still load (see versioned_account!) so
+// handlers keep working on them until migrate_chat / migrate_context runs.
+pub const CONTEXT_VERSION:      u8 = 4;
+pub const CHAT_VERSION:         u8 = 13;
+pub const CHAT_KEY_VERSION:     u8 = 1;
+pub const CHAT_ACCESS_VERSION:  u8 = 1;
+pub const ACCESS_GRANT_VERSION: u8 = 1;
+pub const MESSAGE_VERSION:      u8 = 1;
+pub const CONFIG_VERSION:       u8 = 7;
+pub const MODEL_VERSION:        u8 = 1;
+pub const ORACLE_BOND_VERSION:  u8 = 1;
+pub const DISPUTE_VERSION:      u8 = 1;
+pub const SUBMISSIONS_VERSION:  u8 = 1;
+
+// Leading byte of every emitted StatusChanged / DekResponse; bump on any field change.
+pub const STATUS_CHANGED_SCHEMA_VERSION: u8 = 1;
+pub const DEK_RESPONSE_SCHEMA_VERSION:   u8 = 1;
+
+pub const SECONDS_PER_DAY: i64 = 86_400;
+pub const DEK_ACCESS_LOG_LEN: usize = 8; // ring buffer of recent get_dek callers
+pub const CHAT_RATE_LOG_LEN: usize = 32; // recent create_chat times; caps max_chats_per_window
+
+pub const MESSAGE_ROLE_USER:      u8 = 1;
+pub const MESSAGE_ROLE_ASSISTANT: u8 = 2;
+
+pub const ROLE_NONE:   u8 = 0;
+pub const ROLE_READER: u8 = 1; // may call get_dek
+pub const ROLE_WRITER: u8 = 2; // reader + update_status
+
+// Config::paused bits, one per guarded instruction family
+pub const PAUSE_CREATE_CHAT: u8 = 1 << 0; // create_chat
+pub const PAUSE_GET_DEK:     u8 = 1 << 1; // get_dek, get_message_dek
+pub const PAUSE_DELEGATE:    u8 = 1 << 2; // delegate_chat
+pub const PAUSE_ALL: u8 = PAUSE_CREATE_CHAT | PAUSE_GET_DEK | PAUSE_DELEGATE;
 
 
 
@@ -35,301 +103,3695 @@ pub enum CustomError {
     MathOverflow,
     #[msg("Oracle tx id mismatch.")]
     OracleTxIdMismatch,
+    #[msg("Key epoch does not exist for this chat.")]
+    InvalidKeyEpoch,
+    #[msg("Historical key account does not match the requested epoch.")]
+    ChatKeyMismatch,
+    #[msg("Keys for this chat have been revoked.")]
+    KeysRevoked,
+    #[msg("Unknown access role.")]
+    InvalidRole,
+    #[msg("Member is not on the chat access list.")]
+    MemberNotFound,
+    #[msg("Access grant has expired.")]
+    GrantExpired,
+    #[msg("Access grant has no uses left.")]
+    GrantExhausted,
+    #[msg("Access grant needs a future expiry and at least one use.")]
+    InvalidGrant,
+    #[msg("Provided message index is not the next one for this chat.")]
+    InvalidMessageIndex,
+    #[msg("No cmk given and the context has no master key to derive one.")]
+    MissingMasterKey,
+    #[msg("Chat has already ended.")]
+    CannotCancel,
+    #[msg("Progress percent must be between 0 and 100.")]
+    InvalidProgress,
+    #[msg("Chat is not pending or running.")]
+    ChatNotActive,
+    #[msg("Callback lists too many accounts or targets this program.")]
+    InvalidCallback,
+    #[msg("Callback accounts do not match the ones recorded on the chat.")]
+    CallbackAccountMismatch,
+    #[msg("Chats with a callback must be finished through finalize_chat.")]
+    CallbackRequiresFinalize,
+    #[msg("Model is disabled.")]
+    ModelDisabled,
+    #[msg("Model account does not match the requested model id.")]
+    ModelMismatch,
+    #[msg("Model display name is too long.")]
+    ModelNameTooLong,
+    #[msg("Not enough unreserved credits in the context.")]
+    InsufficientCredits,
+    #[msg("Credit price has not been configured.")]
+    CreditsNotForSale,
+    #[msg("Mint is not accepted for payment.")]
+    MintNotAllowed,
+    #[msg("Mint is already accepted for payment.")]
+    MintAlreadyAllowed,
+    #[msg("Allowed mint list is full.")]
+    TooManyMints,
+    #[msg("Too many chats created in the current window.")]
+    ChatRateLimited,
+    #[msg("Daily spend limit reached.")]
+    DailySpendLimit,
+    #[msg("Too many chats still pending.")]
+    TooManyPendingChats,
+    #[msg("A chat rate limit needs a non-zero window and at most 32 chats.")]
+    InvalidLimits,
+    #[msg("This instruction is paused by the admin.")]
+    Paused,
+    #[msg("Unknown pause flag.")]
+    InvalidPauseFlags,
+    #[msg("Oracle is not registered.")]
+    OracleNotRegistered,
+    #[msg("Oracle is already registered.")]
+    OracleAlreadyRegistered,
+    #[msg("Oracle registry is full.")]
+    TooManyOracles,
+    #[msg("Assigned oracle has not timed out yet.")]
+    OracleNotStale,
+    #[msg("No other oracle to reassign to.")]
+    OracleUnchanged,
+    #[msg("Bond is below the minimum stake.")]
+    BondTooSmall,
+    #[msg("Not enough staked to unstake that amount.")]
+    InsufficientStake,
+    #[msg("An unstake is already pending.")]
+    UnstakePending,
+    #[msg("No unstake is pending.")]
+    NoUnstakePending,
+    #[msg("Unstake cooldown has not elapsed.")]
+    CooldownActive,
+    #[msg("Assigned oracle has not missed its deadline.")]
+    DeadlineNotMissed,
+    #[msg("This fault was already slashed.")]
+    AlreadySlashed,
+    #[msg("Results must go through finalize_chat while a challenge window is configured.")]
+    ResultRequiresFinalize,
+    #[msg("Result is awaiting settlement.")]
+    ResultPendingSettlement,
+    #[msg("Challenge window has closed.")]
+    ChallengeWindowClosed,
+    #[msg("Challenge window is still open.")]
+    ChallengeWindowOpen,
+    #[msg("Result is under dispute.")]
+    DisputeOpen,
+    #[msg("Chat requires k-of-n consensus; use submit_result.")]
+    ConsensusRequired,
+    #[msg("Chat is not in consensus mode.")]
+    NotConsensusChat,
+    #[msg("Invalid number of required confirmations.")]
+    InvalidConfirmations,
+    #[msg("Oracle already submitted a result for this chat.")]
+    DuplicateSubmission,
+    #[msg("Chat was already charged.")]
+    AlreadyCharged,
+    #[msg("Accounts do not match the oracles that agreed on the result.")]
+    SubmitterMismatch,
+    #[msg("A challenge window needs a non-zero dispute bond.")]
+    InvalidDisputeParams,
+    #[msg("A payment mint needs a non-zero price per credit.")]
+    InvalidMintPrice,
+    #[msg("Mint is not the one this chat is paid in.")]
+    PaymentMintMismatch,
+    #[msg("Chat is not done.")]
+    ChatNotDone,
+    #[msg("Too many result submissions for this chat.")]
+    TooManySubmissions,
+    #[msg("Account is on an older layout; migrate it first.")]
+    MigrationRequired,
 }
 
 #[event]
 pub struct DekResponse {
+    pub schema_version: u8, // DEK_RESPONSE_SCHEMA_VERSION
     pub chat: Pubkey,
     pub chat_id: u64,
+    pub context: Pubkey,
+    pub key_epoch: u32,
+    pub message_index: Option<u64>, // set for per-message keys from get_message_dek
+    pub actor: Pubkey,              // the caller
+    pub access_count: u64,          // chat.dek_access_count after this call
+    pub slot: u64,
+    pub timestamp: i64,
     pub dek: [u8; 32],
 }
 
 #[event]
 pub struct StatusChanged {
+    pub schema_version: u8, // STATUS_CHANGED_SCHEMA_VERSION
     pub chat: Pubkey,
     pub chat_id: u64,
+    pub context: Pubkey,
+    pub previous_status: u8,
     pub status: u8,
+    pub actor: Pubkey,
+    pub slot: u64,      // chat.status_changed_slot
+    pub timestamp: i64, // chat.updated_at
+    pub started_at: i64,
+    pub completed_at: i64,
+    pub error_code: ChatErrorCode,
+    pub error_detail_hash: Option<[u8; 32]>,
 }
 
-#[ephemeral]
-#[program]
-pub mod loyal_oracle {
-    use super::*;
+/// Reason attached by the oracle when a chat ends in STATUS_ERROR.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, InitSpace)]
+pub enum ChatErrorCode {
+    None,
+    ModelUnavailable,
+    ContentRefused,
+    Timeout,
+    DecryptionFailure,
+    QuotaExceeded,
+    ResultDisputed,   // result overturned by the arbiter
+    SettlementFailed, // result abandoned after settle_result kept failing
+}
 
-    pub fn initialize(_ctx: Context<Initialize>) -> Result<()> {
-        Ok(())
-    }
+#[event]
+pub struct CreditsChanged {
+    pub context: Pubkey,
+    pub credits: u64,          // total balance, including reservations
+    pub reserved_credits: u64, // held by chats still in flight
+}
 
-    pub fn create_context(ctx: Context<CreateContext>) -> Result<()> {
-        let c = &mut ctx.accounts.context_account;
-        c.owner = ctx.accounts.payer.key();
-        c.next_chat_id = 0;
+#[event]
+pub struct Progress {
+    pub chat: Pubkey,
+    pub chat_id: u64,
+    pub percent: u8,
+    pub stage: u8, // oracle-defined pipeline stage
+}
 
-        Ok(())
-    }
+#[event]
+pub struct ChatCancelled {
+    pub chat: Pubkey,
+    pub chat_id: u64,
+    pub previous_status: u8,
+}
 
-    pub fn create_chat(
-        ctx: Context<CreateChat>,
-        chat_id: u64,
-        cmk: Pubkey,
-        tx_id: Pubkey,
-    ) -> Result<()> {
-        let c = &mut ctx.accounts.chat;
-    
-        let is_new = c.created_at == 0;
-    
-        if is_new {
-            // enforce monotonic sequence for this context (same rule you used before)
-            require!(
-                chat_id == ctx.accounts.context_account.next_chat_id,
-                CustomError::InvalidChatId
-            );
-    
-            c.context = ctx.accounts.context_account.key();
-            c.user = ctx.accounts.payer.key();
-            c.id = chat_id;
-            c.created_at = Clock::get()?.unix_timestamp;
-            c.status = STATUS_PENDING;
-    
-            // encryption fields
-            c.cmk = cmk;
-            c.tx_id = tx_id;
-    
-            // advance counter once per new PDA
-            ctx.accounts.context_account.next_chat_id =
-                ctx.accounts.context_account
-                    .next_chat_id
-                    .checked_add(1)
-                    .ok_or(CustomError::MathOverflow)?;
-        } else {
-            // if chat already exists, we don't do anything
-            require_eq!(c.id, chat_id, CustomError::ChatIdMismatch);
-            require_keys_eq!(c.context, ctx.accounts.context_account.key(), CustomError::ContextMismatch);
-            return Ok(());
-        }
-    
-        Ok(())
-    }
+#[event]
+pub struct MessageAppended {
+    pub chat: Pubkey,
+    pub chat_id: u64,
+    pub index: u64,
+    pub role: u8,
+}
 
-    pub fn get_dek(ctx: Context<GetDek>) -> Result<()> {
-        let caller_key = ctx.accounts.caller.key();
-        let c = &ctx.accounts.chat;
-    
-        // only chat creator OR the oracle identity
-        let is_user = caller_key == c.user;
-        let is_oracle = caller_key == ORACLE_IDENTITY;
-        require!(is_user || is_oracle, CustomError::Unauthorized);
-    
-        // HKDF(CMK, info="file:"+tx_id) -> 32 bytes
-        let cmk_bytes = c.cmk.to_bytes();    // IKM
-        let mut info: [u8; 37] = [0u8; 37];  // "file:" (5) + 32-byte tx_id
-        info[..5].copy_from_slice(b"file:");
-        info[5..].copy_from_slice(&c.tx_id.to_bytes());
-    
-        let kdf = Hkdf::<Sha256>::new(None, &cmk_bytes);
-        let mut dek = [0u8; 32];
-        kdf.expand(&info, &mut dek).map_err(|_| error!(CustomError::HkdfExpandFailed))?;
-    
-        emit!(DekResponse {
-            chat: c.key(),
-            chat_id: c.id,
-            dek: dek,
-        });
-    
-        Ok(())
-    }
+#[event]
+pub struct KeysRevoked {
+    pub context: Pubkey,
+    /// `None` when the whole context was switched off.
+    pub chat: Option<Pubkey>,
+}
 
-    pub fn update_status(
-        ctx: Context<UpdateChatStatus>,
-        new_status: u8,                   // e.g. STATUS_DONE or STATUS_ERROR
-    ) -> Result<()> {
-        let caller_key = ctx.accounts.caller.key();
-        let c = &mut ctx.accounts.chat;
-        let is_user = caller_key == c.user;
-        let is_oracle = caller_key == ORACLE_IDENTITY;
-        require!(is_user || is_oracle, CustomError::Unauthorized);
+#[event]
+pub struct AccessChanged {
+    pub chat: Pubkey,
+    pub member: Pubkey,
+    pub role: u8, // ROLE_NONE once revoked
+}
 
-        require!(
-            new_status == STATUS_DONE || new_status == STATUS_ERROR || new_status == STATUS_PENDING,
-            CustomError::Unauthorized
-        );
-        c.status = new_status;
-    
-        emit!(StatusChanged {
-            chat: c.key(),
-            chat_id: c.id,
-            status: c.status,
-        });
-        Ok(())
-    }
+#[event]
+pub struct KeyRotated {
+    pub chat: Pubkey,
+    pub chat_id: u64,
+    pub key_epoch: u32,
+}
 
-    pub fn delegate_chat(ctx: Context<DelegateChat>, chat_id: u64) -> Result<()> {
-        ctx.accounts.delegate_chat(
-            &ctx.accounts.payer,
-            &[
-                CHAT_SEED,
-                ctx.accounts.context_account.key().as_ref(),
-                &chat_id.to_le_bytes(),
-            ],
-            DelegateConfig {
-                commit_frequency_ms: 0,
-                validator: Some(pubkey!("mAGicPQYBMvcYveUZA5F5UNNwyHvfYh5xkLS2Fr1mev")),
-            },
-        )?;
-        Ok(())
-    }
+#[event]
+pub struct OracleAssigned {
+    pub chat: Pubkey,
+    pub oracle: Pubkey,
+    pub previous: Option<Pubkey>, // None on creation
 }
 
-/// Contexts
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum SlashReason {
+    MissedDeadline,
+    LostDispute,
+}
 
-#[derive(Accounts)]
-pub struct Initialize<'info> {
-    #[account(mut)]
-    pub payer: Signer<'info>,
-    #[account(
-        init_if_needed,
-        payer = payer,
-        space = 8,
-        seeds = [b"identity"],
-        bump
-    )]
-    pub identity: Account<'info, Identity>,
-    pub system_program: Program<'info, System>,
+#[event]
+pub struct OracleStaked {
+    pub operator: Pubkey,
+    pub amount: u64,
+    pub staked: u64, // bond total afterwards
 }
 
-#[derive(Accounts)]
-pub struct CreateContext<'info> {
-    #[account(mut)]
-    pub payer: Signer<'info>,
+#[event]
+pub struct UnstakeRequested {
+    pub operator: Pubkey,
+    pub amount: u64,
+    pub available_at: i64, // unix timestamp
+}
 
-    #[account(
-        init_if_needed,
-        payer = payer,
-        // 8 discr + 32 owner + (fields)
-        space = 8 + 32 + 8 + 4,
-        seeds = [ContextAccount::seed(), payer.key().as_ref()],
-        bump
-    )]
-    pub context_account: Account<'info, ContextAccount>,
-    pub system_program: Program<'info, System>,
+#[event]
+pub struct StakeWithdrawn {
+    pub operator: Pubkey,
+    pub amount: u64,
 }
 
-#[derive(Accounts)]
-#[instruction(chat_id: u64)]
-pub struct CreateChat<'info> {
-    #[account(mut)]
-    pub payer: Signer<'info>,
+#[event]
+pub struct OracleSlashed {
+    pub operator: Pubkey,
+    pub chat: Pubkey,
+    pub reason: SlashReason,
+    pub amount: u64, // lamports actually taken, capped by the bond
+}
 
-    // MUST be the owner; ensures "context uniquely connected to the user"
-    #[account(
-        mut,
-        constraint = context_account.owner == payer.key() @ CustomError::ContextOwnerMismatch
-    )]
-    pub context_account: Account<'info, ContextAccount>,
+#[event]
+pub struct DisputeOpened {
+    pub chat: Pubkey,
+    pub challenger: Pubkey,
+    pub bond: u64,
+    pub evidence_hash: [u8; 32],
+}
 
-    /// creates the interaction PDA if needed
-    #[account(
-        init_if_needed,
-        payer = payer,
-        space = 8 + Chat::INIT_SPACE,
-        seeds = [
-            CHAT_SEED,
-            context_account.key().as_ref(),
-            &chat_id.to_le_bytes(),
-        ],
-        bump
-    )]
-    pub chat: Account<'info, Chat>,
+#[event]
+pub struct DisputeResolved {
+    pub chat: Pubkey,
+    pub challenger: Pubkey,
+    pub upheld: bool, // true = result overturned
+}
 
-    pub system_program: Program<'info, System>,
+#[event]
+pub struct ResultSubmitted {
+    pub chat: Pubkey,
+    pub oracle: Pubkey,
+    pub result_hash: [u8; 32],
+    pub matches: u8, // submissions agreeing with result_hash, this one included
 }
 
-#[derive(Accounts)]
-pub struct GetDek<'info> {
-    /// chat.user OR the oracle identity.
-    #[account(mut)]
-    pub caller: Signer<'info>,
+/// One per submission that disagrees with the hash consensus settled on.
+#[event]
+pub struct ResultMismatch {
+    pub chat: Pubkey,
+    pub oracle: Pubkey,
+    pub result_hash: [u8; 32],
+    pub agreed_hash: [u8; 32],
+}
 
-    /// Must be owned by this program.
-    #[account(mut)]
-    pub chat: Account<'info, Chat>,
+#[event]
+pub struct Paused {
+    pub flags: u8,  // bits newly paused
+    pub paused: u8, // full pause mask afterwards
 }
 
-#[delegate]
-#[derive(Accounts)]
-#[instruction(chat_id: u64)]
-pub struct DelegateChat<'info> {
-    #[account(mut)]
-    pub payer: Signer<'info>,
+#[event]
+pub struct Unpaused {
+    pub flags: u8,  // bits newly cleared
+    pub paused: u8, // full pause mask afterwards
+}
 
-    /// CHECK: the correct chat account
-    #[account(
-        mut, del,
-        seeds = [CHAT_SEED, context_account.key().as_ref(), &chat_id.to_le_bytes()],
-        bump
-    )]
-    /// CHECK: we only use seeds + delegation, no need to deserialize here
-    pub chat: AccountInfo<'info>,
+#[event]
+pub struct TokensDeposited {
+    pub owner: Pubkey,
+    pub mint: Pubkey,
+    pub amount: u64,
+}
 
-    /// CHECK: we accept any context
-    pub context_account: AccountInfo<'info>,
+#[event]
+pub struct TokensWithdrawn {
+    pub owner: Pubkey,
+    pub mint: Pubkey,
+    pub amount: u64,
 }
 
-#[commit]
-#[derive(Accounts)]
-#[instruction(chat_id: u64)]
-pub struct UndelegateChat<'info> {
-    /// CHECK: Matched against the chat account
-    pub user: AccountInfo<'info>,
+#[event]
+pub struct TokensCharged {
+    pub chat: Pubkey,
+    pub owner: Pubkey,
+    pub mint: Pubkey,
+    pub amount: u64,
+}
 
-    #[account(mut)]
-    pub payer: Signer<'info>,
+fn hkdf_expand(ikm: &Pubkey, info: &[u8]) -> Result<[u8; 32]> {
+    let kdf = Hkdf::<Sha256>::new(None, &ikm.to_bytes());
+    let mut okm = [0u8; 32];
+    kdf.expand(info, &mut okm).map_err(|_| error!(CustomError::HkdfExpandFailed))?;
+    Ok(okm)
+}
 
-    #[account(
-        mut,
-        seeds = [CHAT_SEED, context_account.key().as_ref(), &chat_id.to_le_bytes()],
-        bump
-    )]
-    pub chat: Account<'info, Chat>,
+fn derive_dek(cmk: &Pubkey, tx_id: &Pubkey) -> Result<[u8; 32]> {
+    // HKDF(CMK, info="file:"+tx_id) -> 32 bytes
+    let mut info: [u8; 37] = [0u8; 37];  // "file:" (5) + 32-byte tx_id
+    info[..5].copy_from_slice(b"file:");
+    info[5..].copy_from_slice(&tx_id.to_bytes());
+    hkdf_expand(cmk, &info)
+}
 
-    /// CHECK: we accept any context
-    pub context_account: AccountInfo<'info>,
+fn derive_chat_key(master_key: &Pubkey, chat_id: u64) -> Result<Pubkey> {
+    // HKDF(context master key, info="chat:"+chat_id) -> per-chat CMK
+    let mut info: [u8; 13] = [0u8; 13];  // "chat:" (5) + u64 LE
+    info[..5].copy_from_slice(b"chat:");
+    info[5..].copy_from_slice(&chat_id.to_le_bytes());
+    Ok(Pubkey::new_from_array(hkdf_expand(master_key, &info)?))
 }
 
-/// --------------------------------------------------
-/// Accounts
-/// --------------------------------------------------
+fn derive_message_dek(cmk: &Pubkey, chat_id: u64, message_index: u64) -> Result<[u8; 32]> {
+    // HKDF(CMK, info="msg:"+chat_id+message_index) -> 32 bytes
+    let mut info: [u8; 20] = [0u8; 20];  // "msg:" (4) + u64 LE + u64 LE
+    info[..4].copy_from_slice(b"msg:");
+    info[4..12].copy_from_slice(&chat_id.to_le_bytes());
+    info[12..].copy_from_slice(&message_index.to_le_bytes());
+    hkdf_expand(cmk, &info)
+}
 
-#[account]
+/// CMK for `key_epoch`: the latest lives on the chat, older ones in their ChatKey archive.
+fn epoch_cmk(chat: &Account<Chat>, chat_key: &Option<Account<ChatKey>>, key_epoch: u32) -> Result<Pubkey> {
+    if key_epoch == chat.key_epoch {
+        return Ok(chat.cmk);
+    }
+    require!(key_epoch < chat.key_epoch, CustomError::InvalidKeyEpoch);
+    let k = chat_key.as_ref().ok_or(CustomError::InvalidKeyEpoch)?;
+    require_keys_eq!(k.chat, chat.key(), CustomError::ChatKeyMismatch);
+    require_eq!(k.epoch, key_epoch, CustomError::ChatKeyMismatch);
+    Ok(k.cmk)
+}
+
+/// Resizes a program account to the current layout of `T` (zero-filling any new
+/// bytes, topping up rent from `payer`) and re-stamps it through `upgrade`.
+/// Lamports above rent go to `refund` when given and otherwise stay put, so a
+/// permissionless caller can never collect them.
+fn migrate_layout<'info, T: AccountSerialize + AccountDeserialize>(
+    info: &AccountInfo<'info>,
+    payer: &AccountInfo<'info>,
+    refund: Option<&AccountInfo<'info>>,
+    system_program: &AccountInfo<'info>,
+    space: usize,
+    upgrade: impl FnOnce(&mut T) -> Result<()>,
+) -> Result<()> {
+    let rent = Rent::get()?.minimum_balance(space);
+    let balance = info.lamports();
+    if rent > balance {
+        system_program::transfer(
+            CpiContext::new(
+                system_program.clone(),
+                system_program::Transfer {
+                    from: payer.clone(),
+                    to: info.clone(),
+                },
+            ),
+            rent - balance,
+        )?;
+    } else if let Some(refund) = refund.filter(|_| balance > rent) {
+        **info.try_borrow_mut_lamports()? -= balance - rent;
+        **refund.try_borrow_mut_lamports()? += balance - rent;
+    }
+    info.resize(space)?;
+
+    let mut data = info.try_borrow_mut_data()?;
+    let mut state = T::try_deserialize(&mut &data[..])?; // also checks the discriminator
+    upgrade(&mut state)?;
+    state.try_serialize(&mut &mut data[..])?;
+    Ok(())
+}
+
+/// Deserializes an account body that may still be on an older layout. Layouts
+/// are append-only, so a shorter body is an older version and loads as if
+/// migrate_layout had already zero-filled it.
+fn load_layout<T: AnchorDeserialize>(data: &[u8], space: usize) -> Result<T> {
+    let state = if data.len() >= space {
+        T::deserialize(&mut &data[..])
+    } else {
+        let mut padded = data.to_vec();
+        padded.resize(space, 0);
+        T::deserialize(&mut &padded[..])
+    };
+    state.map_err(|_| anchor_lang::error::ErrorCode::AccountDidNotDeserialize.into())
+}
+
+/// Serializes into an account that may still be on an older layout: what fits
+/// is written, and whatever doesn't must be zero, i.e. left as the zero fill of
+/// a migration would leave it. Anything else needs the account migrated first.
+fn store_layout<T: AnchorSerialize, W: std::io::Write>(state: &T, writer: &mut W) -> Result<()> {
+    let mut bytes = Vec::new();
+    state
+        .serialize(&mut bytes)
+        .map_err(|_| anchor_lang::error::ErrorCode::AccountDidNotSerialize)?;
+    let mut written = 0;
+    while written < bytes.len() {
+        match writer.write(&bytes[written..]) {
+            Ok(0) => break,
+            Ok(n) => written += n,
+            Err(_) => return err!(anchor_lang::error::ErrorCode::AccountDidNotSerialize),
+        }
+    }
+    require!(bytes[written..].iter().all(|b| *b == 0), CustomError::MigrationRequired);
+    Ok(())
+}
+
+/// What `#[account]` generates, with loading and storing going through
+/// load_layout / store_layout so that accounts deployed on an older layout stay
+/// usable during the transition. The discriminator is the one `#[account]`
+/// would derive: sha256("account:<Name>")[..8].
+macro_rules! versioned_account {
+    ($name:ident, $discriminator:expr) => {
+        impl Discriminator for $name {
+            const DISCRIMINATOR: &'static [u8] = &$discriminator;
+        }
+
+        impl Owner for $name {
+            fn owner() -> Pubkey {
+                crate::ID
+            }
+        }
+
+        impl AccountSerialize for $name {
+            fn try_serialize<W: std::io::Write>(&self, writer: &mut W) -> Result<()> {
+                writer
+                    .write_all(Self::DISCRIMINATOR)
+                    .map_err(|_| anchor_lang::error::ErrorCode::AccountDidNotSerialize)?;
+                store_layout(self, writer)
+            }
+        }
+
+        impl AccountDeserialize for $name {
+            fn try_deserialize(buf: &mut &[u8]) -> Result<Self> {
+                let disc = Self::DISCRIMINATOR;
+                if buf.len() < disc.len() {
+                    return err!(anchor_lang::error::ErrorCode::AccountDiscriminatorNotFound);
+                }
+                if &buf[..disc.len()] != disc {
+                    return Err(error!(anchor_lang::error::ErrorCode::AccountDiscriminatorMismatch)
+                        .with_account_name(stringify!($name)));
+                }
+                Self::try_deserialize_unchecked(buf)
+            }
+
+            fn try_deserialize_unchecked(buf: &mut &[u8]) -> Result<Self> {
+                load_layout(&buf[Self::DISCRIMINATOR.len()..], Self::INIT_SPACE)
+            }
+        }
+    };
+}
+
+/// Writes the next message of a conversation and advances the chat's cursor.
+fn append(
+    chat: &mut Account<Chat>,
+    message: &mut Account<Message>,
+    index: u64,
+    role: u8,
+    content: Pubkey,
+    content_hash: [u8; 32],
+) -> Result<()> {
+    require_eq!(index, chat.next_message_index, CustomError::InvalidMessageIndex);
+
+    message.chat = chat.key();
+    message.index = index;
+    message.role = role;
+    message.content = content;
+    message.content_hash = content_hash;
+    message.created_at = Clock::get()?.unix_timestamp;
+    message.version = MESSAGE_VERSION;
+
+    chat.next_message_index = index.checked_add(1).ok_or(CustomError::MathOverflow)?;
+
+    emit!(MessageAppended {
+        chat: chat.key(),
+        chat_id: chat.id,
+        index,
+        role,
+    });
+    Ok(())
+}
+
+fn status_changed(c: &Account<Chat>, previous_status: u8, actor: Pubkey) -> StatusChanged {
+    StatusChanged {
+        schema_version: STATUS_CHANGED_SCHEMA_VERSION,
+        chat: c.key(),
+        chat_id: c.id,
+        context: c.context,
+        previous_status,
+        status: c.status,
+        actor,
+        slot: c.status_changed_slot,
+        timestamp: c.updated_at,
+        started_at: c.started_at,
+        completed_at: c.completed_at,
+        error_code: c.error_code,
+        error_detail_hash: c.error_detail_hash,
+    }
+}
+
+fn dek_response(
+    c: &Account<Chat>,
+    key_epoch: u32,
+    message_index: Option<u64>,
+    actor: Pubkey,
+    clock: &Clock,
+    dek: [u8; 32],
+) -> DekResponse {
+    DekResponse {
+        schema_version: DEK_RESPONSE_SCHEMA_VERSION,
+        chat: c.key(),
+        chat_id: c.id,
+        context: c.context,
+        key_epoch,
+        message_index,
+        actor,
+        access_count: c.dek_access_count,
+        slot: clock.slot,
+        timestamp: clock.unix_timestamp,
+        dek,
+    }
+}
+
+/// CPI into a consumer program: data is `discriminator || chat || chat_id || result_hash`,
+/// accounts are the signing callback authority followed by the recorded list.
+fn invoke_callback<'info>(
+    cb: &Callback,
+    chat: Pubkey,
+    chat_id: u64,
+    result_hash: [u8; 32],
+    authority: &AccountInfo<'info>,
+    authority_bump: u8,
+    remaining: &[AccountInfo<'info>],
+) -> Result<()> {
+    let program = remaining
+        .first()
+        .ok_or(CustomError::CallbackAccountMismatch)?;
+    require_keys_eq!(program.key(), cb.program_id, CustomError::CallbackAccountMismatch);
+    let accounts = &remaining[1..];
+    require_eq!(accounts.len(), cb.accounts.len(), CustomError::CallbackAccountMismatch);
+
+    let mut metas = Vec::with_capacity(cb.accounts.len() + 1);
+    let mut infos = Vec::with_capacity(cb.accounts.len() + 2);
+    metas.push(AccountMeta::new_readonly(authority.key(), true));
+    infos.push(authority.clone());
+    for (expected, info) in cb.accounts.iter().zip(accounts) {
+        require_keys_eq!(info.key(), expected.pubkey, CustomError::CallbackAccountMismatch);
+        metas.push(if expected.is_writable {
+            AccountMeta::new(expected.pubkey, false)
+        } else {
+            AccountMeta::new_readonly(expected.pubkey, false)
+        });
+        infos.push(info.clone());
+    }
+    infos.push(program.clone());
+
+    let mut data = Vec::with_capacity(8 + 32 + 8 + 32);
+    data.extend_from_slice(&cb.discriminator);
+    data.extend_from_slice(chat.as_ref());
+    data.extend_from_slice(&chat_id.to_le_bytes());
+    data.extend_from_slice(&result_hash);
+
+    invoke_signed(
+        &Instruction {
+            program_id: cb.program_id,
+            accounts: metas,
+            data,
+        },
+        &infos,
+        &[&[CALLBACK_AUTHORITY_SEED, &[authority_bump]]],
+    )?;
+    Ok(())
+}
+
+/// `transfer_checked` through whichever token program owns the mint, so SPL
+/// Token and Token-2022 mints share one code path.
+fn transfer_tokens<'info>(
+    token_program: &Interface<'info, TokenInterface>,
+    from: &InterfaceAccount<'info, TokenAccount>,
+    to: &InterfaceAccount<'info, TokenAccount>,
+    mint: &InterfaceAccount<'info, Mint>,
+    authority: AccountInfo<'info>,
+    signer_seeds: &[&[&[u8]]],
+    amount: u64,
+) -> Result<()> {
+    token_interface::transfer_checked(
+        CpiContext::new_with_signer(
+            token_program.to_account_info(),
+            TransferChecked {
+                from: from.to_account_info(),
+                mint: mint.to_account_info(),
+                to: to.to_account_info(),
+                authority,
+            },
+            signer_seeds,
+        ),
+        amount,
+        mint.decimals,
+    )
+}
+
+/// Takes up to `amount` lamports from a bond, staked funds first and then any
+/// pending unstake, into `recipient`. Drops the operator from the registry once
+/// the remaining stake is under the minimum.
+fn slash_bond(
+    config: &mut Config,
+    bond: &mut OracleBond,
+    bond_info: &AccountInfo,
+    recipient: &AccountInfo,
+    chat: Pubkey,
+    reason: SlashReason,
+    amount: u64,
+) -> Result<()> {
+    let from_stake = amount.min(bond.staked);
+    let from_unstake = (amount - from_stake).min(bond.unstake_amount);
+    let taken = from_stake + from_unstake;
+    bond.staked -= from_stake;
+    bond.unstake_amount -= from_unstake;
+    bond.slashed_total = bond.slashed_total.saturating_add(taken);
+
+    **bond_info.try_borrow_mut_lamports()? -= taken;
+    **recipient.try_borrow_mut_lamports()? += taken;
+
+    if bond.staked < config.min_oracle_bond {
+        config.deregister_oracle(&bond.operator);
+    }
+
+    emit!(OracleSlashed {
+        operator: bond.operator,
+        chat,
+        reason,
+        amount: taken,
+    });
+    Ok(())
+}
+
+/// `slash_bond` for a bond PDA passed unchecked: an oracle that never staked
+/// has no program-owned bond and nothing to take.
+fn slash_if_staked(
+    config: &mut Config,
+    bond_info: &AccountInfo,
+    recipient: &AccountInfo,
+    chat: Pubkey,
+    reason: SlashReason,
+    amount: u64,
+) -> Result<()> {
+    if bond_info.owner != &crate::ID {
+        return Ok(());
+    }
+    let mut bond = OracleBond::try_deserialize(&mut &bond_info.try_borrow_data()?[..])?;
+    slash_bond(config, &mut bond, bond_info, recipient, chat, reason, amount)?;
+    bond.try_serialize(&mut &mut bond_info.try_borrow_mut_data()?[..])
+}
+
+/// Pairs each oracle that agreed on a consensus result with its bond PDA,
+/// taken from `remaining` as `[oracle, bond]` in submission order.
+fn agreeing_submitters<'a, 'info>(
+    submissions: &ResultSubmissions,
+    remaining: &'a [AccountInfo<'info>],
+) -> Result<Vec<(&'a AccountInfo<'info>, &'a AccountInfo<'info>)>> {
+    let agreed = submissions.agreed_hash.ok_or(CustomError::SubmitterMismatch)?;
+    let oracles: Vec<Pubkey> = submissions
+        .submissions
+        .iter()
+        .filter(|x| x.result_hash == agreed)
+        .map(|x| x.oracle)
+        .collect();
+    require!(remaining.len() == oracles.len() * 2, CustomError::SubmitterMismatch);
+
+    oracles
+        .iter()
+        .zip(remaining.chunks(2))
+        .map(|(oracle, pair)| {
+            let (bond, _) =
+                Pubkey::find_program_address(&[ORACLE_BOND_SEED, oracle.as_ref()], &crate::ID);
+            require!(
+                pair[0].key() == *oracle && pair[1].key() == bond,
+                CustomError::SubmitterMismatch
+            );
+            Ok((&pair[0], &pair[1]))
+        })
+        .collect()
+}
+
+/// Accepts a chat's recorded result: DONE, credits settled and the consumer
+/// callback, if any, invoked.
+fn complete_chat<'info>(
+    c: &mut Account<'info, Chat>,
+    context: &mut Account<'info, ContextAccount>,
+    callback_authority: &AccountInfo<'info>,
+    callback_authority_bump: u8,
+    remaining: &[AccountInfo<'info>],
+) -> Result<()> {
+    let result_hash = c.result_hash.ok_or(CustomError::ChatNotActive)?;
+    c.set_status(STATUS_DONE, &Clock::get()?);
+    c.error_code = ChatErrorCode::None;
+    c.error_detail_hash = None;
+    settle_chat(context, c)?;
+
+    if let Some(cb) = &c.callback {
+        invoke_callback(
+            cb,
+            c.key(),
+            c.id,
+            result_hash,
+            callback_authority,
+            callback_authority_bump,
+            remaining,
+        )?;
+    }
+    Ok(())
+}
+
+fn credits_changed(c: &Account<ContextAccount>) -> CreditsChanged {
+    CreditsChanged {
+        context: c.key(),
+        credits: c.credits,
+        reserved_credits: c.reserved_credits,
+    }
+}
+
+/// Settles a chat once it ends: its credit reservation is consumed on DONE or
+/// released on ERROR / CANCELLED, and its pending slot is freed. No-op while
+/// the chat is still open.
+fn settle_chat(context: &mut Account<ContextAccount>, chat: &mut Account<Chat>) -> Result<()> {
+    if !matches!(chat.status, STATUS_DONE | STATUS_ERROR | STATUS_CANCELLED) {
+        return Ok(());
+    }
+    if chat.counts_as_pending {
+        context.pending_chats = context.pending_chats.saturating_sub(1);
+        chat.counts_as_pending = false;
+    }
+
+    let amount = chat.reserved_credits;
+    if amount == 0 {
+        return Ok(());
+    }
+    if chat.status == STATUS_DONE {
+        context.credits = context
+            .credits
+            .checked_sub(amount)
+            .ok_or(CustomError::MathOverflow)?;
+    }
+    context.reserved_credits = context
+        .reserved_credits
+        .checked_sub(amount)
+        .ok_or(CustomError::MathOverflow)?;
+    chat.reserved_credits = 0;
+
+    emit!(credits_changed(context));
+    Ok(())
+}
+
+/// Role granted to `who` by the chat's access list, if one was passed in.
+fn access_role(access: &Option<Account<ChatAccess>>, chat: &Pubkey, who: &Pubkey) -> u8 {
+    match access {
+        Some(a) if a.chat == *chat => a.role_of(who),
+        _ => ROLE_NONE,
+    }
+}
+
+#[ephemeral]
+#[program]
+pub mod loyal_oracle {
+    use super::*;
+
+    pub fn initialize(_ctx: Context<Initialize>) -> Result<()> {
+        Ok(())
+    }
+
+    /// One-time setup; the oracle identity bootstraps itself as the first admin.
+    pub fn init_config(ctx: Context<InitConfig>) -> Result<()> {
+        let c = &mut ctx.accounts.config;
+        c.admin = ctx.accounts.admin.key();
+        c.version = CONFIG_VERSION;
+        c.lamports_per_credit = 0;
+        c.allowed_mints = Vec::new();
+        c.paused = 0;
+        c.oracles = Vec::new();
+        c.next_oracle = 0;
+        c.min_oracle_bond = 0;
+        c.unstake_cooldown_secs = 0;
+        c.deadline_slash = 0;
+        c.deadline_secs = 0;
+        c.arbiter = c.admin;
+        c.challenge_window_secs = 0;
+        c.dispute_bond = 0;
+        c.dispute_slash = 0;
+        Ok(())
+    }
+
+    pub fn set_admin(ctx: Context<AdminOnly>, new_admin: Pubkey) -> Result<()> {
+        ctx.accounts.config.admin = new_admin;
+        Ok(())
+    }
+
+    /// Emergency stop for the instruction families in `flags`.
+    pub fn pause(ctx: Context<AdminOnly>, flags: u8) -> Result<()> {
+        require!(flags != 0 && flags & !PAUSE_ALL == 0, CustomError::InvalidPauseFlags);
+        let c = &mut ctx.accounts.config;
+        c.paused |= flags;
+
+        emit!(Paused {
+            flags,
+            paused: c.paused,
+        });
+        Ok(())
+    }
+
+    pub fn unpause(ctx: Context<AdminOnly>, flags: u8) -> Result<()> {
+        require!(flags != 0 && flags & !PAUSE_ALL == 0, CustomError::InvalidPauseFlags);
+        let c = &mut ctx.accounts.config;
+        c.paused &= !flags;
+
+        emit!(Unpaused {
+            flags,
+            paused: c.paused,
+        });
+        Ok(())
+    }
+
+    pub fn add_oracle(ctx: Context<AdminOnly>, oracle: Pubkey) -> Result<()> {
+        let c = &mut ctx.accounts.config;
+        require!(!c.oracles.contains(&oracle), CustomError::OracleAlreadyRegistered);
+        require!(c.oracles.len() < ORACLES_MAX, CustomError::TooManyOracles);
+        c.oracles.push(oracle);
+        Ok(())
+    }
+
+    /// Deregisters `oracle`; its open chats become reassignable by their owners.
+    pub fn remove_oracle(ctx: Context<AdminOnly>, oracle: Pubkey) -> Result<()> {
+        let c = &mut ctx.accounts.config;
+        let idx = c
+            .oracles
+            .iter()
+            .position(|o| *o == oracle)
+            .ok_or(CustomError::OracleNotRegistered)?;
+        c.oracles.remove(idx);
+        c.next_oracle = 0;
+        Ok(())
+    }
+
+    pub fn set_dispute_params(
+        ctx: Context<AdminOnly>,
+        arbiter: Pubkey,
+        challenge_window_secs: i64,
+        dispute_bond: u64,
+        dispute_slash: u64,
+    ) -> Result<()> {
+        // an unbonded challenge window would let anyone stall every result for free
+        require!(
+            challenge_window_secs <= 0 || dispute_bond > 0,
+            CustomError::InvalidDisputeParams
+        );
+        let c = &mut ctx.accounts.config;
+        c.arbiter = arbiter;
+        c.challenge_window_secs = challenge_window_secs.max(0);
+        c.dispute_bond = dispute_bond;
+        c.dispute_slash = dispute_slash;
+        Ok(())
+    }
+
+    pub fn set_bond_params(
+        ctx: Context<AdminOnly>,
+        min_oracle_bond: u64,
+        unstake_cooldown_secs: i64,
+        deadline_slash: u64,
+        deadline_secs: i64,
+    ) -> Result<()> {
+        let c = &mut ctx.accounts.config;
+        c.min_oracle_bond = min_oracle_bond;
+        c.unstake_cooldown_secs = unstake_cooldown_secs.max(0);
+        c.deadline_slash = deadline_slash;
+        c.deadline_secs = deadline_secs.max(0);
+        Ok(())
+    }
+
+    /// Adds lamports to the operator's bond; reaching the minimum registers it
+    /// as an oracle.
+    pub fn stake(ctx: Context<Stake>, amount: u64) -> Result<()> {
+        system_program::transfer(
+            CpiContext::new(
+                ctx.accounts.system_program.to_account_info(),
+                system_program::Transfer {
+                    from: ctx.accounts.operator.to_account_info(),
+                    to: ctx.accounts.bond.to_account_info(),
+                },
+            ),
+            amount,
+        )?;
+
+        let operator = ctx.accounts.operator.key();
+        let b = &mut ctx.accounts.bond;
+        b.operator = operator;
+        b.staked = b.staked.checked_add(amount).ok_or(CustomError::MathOverflow)?;
+        b.version = ORACLE_BOND_VERSION;
+
+        let config = &mut ctx.accounts.config;
+        require!(
+            config.min_oracle_bond > 0 && b.staked >= config.min_oracle_bond,
+            CustomError::BondTooSmall
+        );
+        if !config.oracles.contains(&operator) {
+            require!(config.oracles.len() < ORACLES_MAX, CustomError::TooManyOracles);
+            config.oracles.push(operator);
+        }
+
+        emit!(OracleStaked {
+            operator,
+            amount,
+            staked: b.staked,
+        });
+        Ok(())
+    }
+
+    /// Starts the cooldown on `amount`. Funds stay slashable until withdrawn,
+    /// and dropping under the minimum deregisters the oracle right away.
+    pub fn request_unstake(ctx: Context<ManageStake>, amount: u64) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        let b = &mut ctx.accounts.bond;
+        require!(b.unstake_amount == 0, CustomError::UnstakePending);
+        require!(amount > 0 && amount <= b.staked, CustomError::InsufficientStake);
+
+        let now = Clock::get()?.unix_timestamp;
+        b.staked -= amount;
+        b.unstake_amount = amount;
+        b.unstake_requested_at = now;
+        if b.staked < config.min_oracle_bond {
+            config.deregister_oracle(&b.operator);
+        }
+
+        emit!(UnstakeRequested {
+            operator: b.operator,
+            amount,
+            available_at: now.saturating_add(config.unstake_cooldown_secs),
+        });
+        Ok(())
+    }
+
+    pub fn withdraw_stake(ctx: Context<ManageStake>) -> Result<()> {
+        let cooldown = ctx.accounts.config.unstake_cooldown_secs;
+        let b = &mut ctx.accounts.bond;
+        require!(b.unstake_amount > 0, CustomError::NoUnstakePending);
+        require!(
+            Clock::get()?.unix_timestamp >= b.unstake_requested_at.saturating_add(cooldown),
+            CustomError::CooldownActive
+        );
+
+        let amount = b.unstake_amount;
+        b.unstake_amount = 0;
+        b.unstake_requested_at = 0;
+
+        let bond_info = b.to_account_info();
+        **bond_info.try_borrow_mut_lamports()? -= amount;
+        **ctx.accounts.operator.to_account_info().try_borrow_mut_lamports()? += amount;
+
+        emit!(StakeWithdrawn {
+            operator: b.operator,
+            amount,
+        });
+        Ok(())
+    }
+
+    /// Permissionless: anyone can prove an assigned oracle let an open chat sit
+    /// past the configured deadline, counted from its assignment or its last
+    /// sign of life. Chats the oracle could not serve, because their keys are revoked
+    /// or DEK issuance is paused, are not its fault. Each assignment is
+    /// slashed at most once.
+    pub fn slash_missed_deadline(ctx: Context<SlashOracle>) -> Result<()> {
+        let c = &mut ctx.accounts.chat;
+        require!(
+            matches!(c.status, STATUS_PENDING | STATUS_RUNNING),
+            CustomError::ChatNotActive
+        );
+        require!(
+            !c.keys_revoked && !ctx.accounts.context_account.keys_revoked,
+            CustomError::KeysRevoked
+        );
+        require!(!ctx.accounts.config.is_paused(PAUSE_GET_DEK), CustomError::Paused);
+        require!(
+            c.oracle_idle_secs(Clock::get()?.unix_timestamp)
+                >= ctx.accounts.config.oracle_timeout_secs(),
+            CustomError::DeadlineNotMissed
+        );
+        require!(!c.oracle_slashed, CustomError::AlreadySlashed);
+        c.oracle_slashed = true;
+
+        let amount = ctx.accounts.config.deadline_slash;
+        let bond_info = ctx.accounts.bond.to_account_info();
+        slash_bond(
+            &mut ctx.accounts.config,
+            &mut ctx.accounts.bond,
+            &bond_info,
+            &ctx.accounts.deposit.to_account_info(),
+            c.key(),
+            SlashReason::MissedDeadline,
+            amount,
+        )
+    }
+
+    pub fn set_credit_price(ctx: Context<AdminOnly>, lamports_per_credit: u64) -> Result<()> {
+        ctx.accounts.config.lamports_per_credit = lamports_per_credit;
+        Ok(())
+    }
+
+    pub fn migrate_config(ctx: Context<MigrateConfig>) -> Result<()> {
+        let admin = ctx.accounts.admin.key();
+        migrate_layout::<Config>(
+            &ctx.accounts.config,
+            &ctx.accounts.admin,
+            Some(&ctx.accounts.admin),
+            &ctx.accounts.system_program,
+            8 + Config::INIT_SPACE,
+            |c| {
+                require_keys_eq!(c.admin, admin, CustomError::Unauthorized);
+                c.version = CONFIG_VERSION;
+                Ok(())
+            },
+        )
+    }
+
+    /// Moves lamports collected from credit sales out of the deposit PDA.
+    pub fn withdraw_deposits(ctx: Context<WithdrawDeposits>, lamports: u64) -> Result<()> {
+        system_program::transfer(
+            CpiContext::new_with_signer(
+                ctx.accounts.system_program.to_account_info(),
+                system_program::Transfer {
+                    from: ctx.accounts.deposit.to_account_info(),
+                    to: ctx.accounts.admin.to_account_info(),
+                },
+                &[&[DEPOSIT_PDA_SEED, &[ctx.bumps.deposit]]],
+            ),
+            lamports,
+        )
+    }
+
+    pub fn buy_credits(ctx: Context<BuyCredits>, credits: u64) -> Result<()> {
+        let price = ctx.accounts.config.lamports_per_credit;
+        require!(price > 0, CustomError::CreditsNotForSale);
+        let lamports = credits.checked_mul(price).ok_or(CustomError::MathOverflow)?;
+
+        system_program::transfer(
+            CpiContext::new(
+                ctx.accounts.system_program.to_account_info(),
+                system_program::Transfer {
+                    from: ctx.accounts.owner.to_account_info(),
+                    to: ctx.accounts.deposit.to_account_info(),
+                },
+            ),
+            lamports,
+        )?;
+
+        let c = &mut ctx.accounts.context_account;
+        c.credits = c.credits.checked_add(credits).ok_or(CustomError::MathOverflow)?;
+
+        emit!(credits_changed(c));
+        Ok(())
+    }
+
+    pub fn add_allowed_mint(
+        ctx: Context<AdminOnly>,
+        mint: Pubkey,
+        units_per_credit: u64,
+    ) -> Result<()> {
+        require!(units_per_credit > 0, CustomError::InvalidMintPrice);
+        let c = &mut ctx.accounts.config;
+        require!(c.allowed_mint(&mint).is_none(), CustomError::MintAlreadyAllowed);
+        require!(c.allowed_mints.len() < ALLOWED_MINTS_MAX, CustomError::TooManyMints);
+        c.allowed_mints.push(AllowedMint {
+            mint,
+            units_per_credit,
+        });
+        Ok(())
+    }
+
+    /// Stops new deposits of `mint`; existing vaults can still be charged and withdrawn.
+    pub fn remove_allowed_mint(ctx: Context<AdminOnly>, mint: Pubkey) -> Result<()> {
+        let c = &mut ctx.accounts.config;
+        let idx = c
+            .allowed_mints
+            .iter()
+            .position(|m| m.mint == mint)
+            .ok_or(CustomError::MintNotAllowed)?;
+        c.allowed_mints.swap_remove(idx);
+        Ok(())
+    }
+
+    pub fn deposit_tokens(ctx: Context<DepositTokens>, amount: u64) -> Result<()> {
+        let a = &ctx.accounts;
+        transfer_tokens(
+            &a.token_program,
+            &a.source,
+            &a.vault,
+            &a.mint,
+            a.owner.to_account_info(),
+            &[],
+            amount,
+        )?;
+
+        emit!(TokensDeposited {
+            owner: a.owner.key(),
+            mint: a.mint.key(),
+            amount,
+        });
+        Ok(())
+    }
+
+    pub fn withdraw_tokens(ctx: Context<WithdrawTokens>, amount: u64) -> Result<()> {
+        let a = &ctx.accounts;
+        transfer_tokens(
+            &a.token_program,
+            &a.vault,
+            &a.destination,
+            &a.mint,
+            a.token_authority.to_account_info(),
+            &[&[TOKEN_AUTHORITY_SEED, &[ctx.bumps.token_authority]]],
+            amount,
+        )?;
+
+        emit!(TokensWithdrawn {
+            owner: a.owner.key(),
+            mint: a.mint.key(),
+            amount,
+        });
+        Ok(())
+    }
+
+    /// Oracle bills a chat to its owner's vault for `mint`.
+    /// Takes a token-paid chat's price from the owner's vault once it is DONE,
+    /// so failed, cancelled or overturned chats never cost anything.
+    pub fn charge_tokens(ctx: Context<ChargeTokens>) -> Result<()> {
+        require!(ctx.accounts.chat.status == STATUS_DONE, CustomError::ChatNotDone);
+        require!(!ctx.accounts.chat.tokens_charged, CustomError::AlreadyCharged);
+
+        let a = &ctx.accounts;
+        let amount = a.chat.tokens_due;
+        transfer_tokens(
+            &a.token_program,
+            &a.vault,
+            &a.treasury,
+            &a.mint,
+            a.token_authority.to_account_info(),
+            &[&[TOKEN_AUTHORITY_SEED, &[ctx.bumps.token_authority]]],
+            amount,
+        )?;
+
+        let c = &mut ctx.accounts.chat;
+        c.tokens_charged = true;
+        emit!(TokensCharged {
+            chat: c.key(),
+            owner: c.user,
+            mint: ctx.accounts.mint.key(),
+            amount,
+        });
+        Ok(())
+    }
+
+    pub fn withdraw_token_treasury(ctx: Context<WithdrawTokenTreasury>, amount: u64) -> Result<()> {
+        let a = &ctx.accounts;
+        transfer_tokens(
+            &a.token_program,
+            &a.treasury,
+            &a.destination,
+            &a.mint,
+            a.token_authority.to_account_info(),
+            &[&[TOKEN_AUTHORITY_SEED, &[ctx.bumps.token_authority]]],
+            amount,
+        )
+    }
+
+    pub fn register_model(
+        ctx: Context<RegisterModel>,
+        model_id: u32,
+        display_name: String,
+        price_per_request: u64,
+        max_context_size: u32,
+    ) -> Result<()> {
+        require!(
+            display_name.len() <= MODEL_NAME_MAX_LEN,
+            CustomError::ModelNameTooLong
+        );
+        let m = &mut ctx.accounts.model;
+        m.model_id = model_id;
+        m.display_name = display_name;
+        m.price_per_request = price_per_request;
+        m.max_context_size = max_context_size;
+        m.enabled = true;
+        m.version = MODEL_VERSION;
+        Ok(())
+    }
+
+    pub fn update_model(
+        ctx: Context<UpdateModel>,
+        display_name: String,
+        price_per_request: u64,
+        max_context_size: u32,
+        enabled: bool,
+    ) -> Result<()> {
+        require!(
+            display_name.len() <= MODEL_NAME_MAX_LEN,
+            CustomError::ModelNameTooLong
+        );
+        let m = &mut ctx.accounts.model;
+        m.display_name = display_name;
+        m.price_per_request = price_per_request;
+        m.max_context_size = max_context_size;
+        m.enabled = enabled;
+        Ok(())
+    }
+
+    pub fn create_context(ctx: Context<CreateContext>) -> Result<()> {
+        let c = &mut ctx.accounts.context_account;
+        // init_if_needed: an existing context keeps its chat cursor, credits and limits
+        if c.owner != Pubkey::default() {
+            return Ok(());
+        }
+        c.owner = ctx.accounts.owner.key();
+        c.next_chat_id = 0;
+        c.keys_revoked = false;
+        c.version = CONTEXT_VERSION;
+        c.master_key = None;
+        c.credits = 0;
+        c.reserved_credits = 0;
+        c.limits = ContextLimits::default();
+        c.recent_chat_count = 0;
+        c.recent_chats = [0; CHAT_RATE_LOG_LEN];
+        c.spend_day = 0;
+        c.spent_today = 0;
+        c.pending_chats = 0;
+
+        Ok(())
+    }
+
+    pub fn create_chat(
+        ctx: Context<CreateChat>,
+        chat_id: u64,
+        cmk: Option<Pubkey>, // None derives it from the context master key
+        tx_id: Pubkey,
+        callback: Option<Callback>,
+        model_id: u32,
+        oracle: Option<Pubkey>,       // None = next oracle in round-robin order
+        payment_mint: Option<Pubkey>, // None = pay with credits
+    ) -> Result<()> {
+        require_eq!(ctx.accounts.model.model_id, model_id, CustomError::ModelMismatch);
+        if let Some(cb) = &callback {
+            require!(
+                cb.accounts.len() <= CALLBACK_MAX_ACCOUNTS && cb.program_id != crate::ID,
+                CustomError::InvalidCallback
+            );
+        }
+        let c = &mut ctx.accounts.chat;
+    
+        let is_new = c.created_at == 0;
+    
+        if is_new {
+            // enforce monotonic sequence for this context (same rule you used before)
+            require!(
+                chat_id == ctx.accounts.context_account.next_chat_id,
+                CustomError::InvalidChatId
+            );
+    
+            c.context = ctx.accounts.context_account.key();
+            c.user = ctx.accounts.owner.key();
+            c.id = chat_id;
+            let clock = Clock::get()?;
+            c.created_at = clock.unix_timestamp;
+            c.status = STATUS_PENDING;
+            c.updated_at = clock.unix_timestamp;
+            c.started_at = 0;
+            c.completed_at = 0;
+            c.status_changed_slot = clock.slot;
+    
+            // encryption fields
+            c.cmk = match cmk {
+                Some(cmk) => cmk,
+                None => {
+                    let master_key = ctx
+                        .accounts
+                        .context_account
+                        .master_key
+                        .ok_or(CustomError::MissingMasterKey)?;
+                    derive_chat_key(&master_key, chat_id)?
+                }
+            };
+            c.tx_id = tx_id;
+            c.key_epoch = 0;
+            c.keys_revoked = false;
+            c.version = CHAT_VERSION;
+            c.next_message_index = 0;
+            c.error_code = ChatErrorCode::None;
+            c.error_detail_hash = None;
+            c.progress_percent = 0;
+            c.progress_stage = 0;
+            c.callback = callback;
+            c.result_hash = None;
+            c.model_id = model_id;
+
+            // hold the model's price until the chat settles
+            let price = ctx.accounts.model.price_per_request;
+            let ctx_acc = &mut ctx.accounts.context_account;
+            ctx_acc.record_chat(price, clock.unix_timestamp)?;
+            c.counts_as_pending = true;
+            c.oracle = ctx.accounts.config.pick_oracle(oracle)?;
+            c.oracle_seen_at = clock.unix_timestamp;
+            emit!(OracleAssigned {
+                chat: c.key(),
+                oracle: c.oracle,
+                previous: None,
+            });
+            c.payment_mint = payment_mint;
+            c.tokens_charged = false;
+            if let Some(mint) = payment_mint {
+                // paid in tokens once DONE; nothing is held in credits
+                let units = ctx
+                    .accounts
+                    .config
+                    .allowed_mint(&mint)
+                    .ok_or(CustomError::MintNotAllowed)?
+                    .units_per_credit;
+                c.tokens_due = price.checked_mul(units).ok_or(CustomError::MathOverflow)?;
+                c.reserved_credits = 0;
+            } else {
+                let available = ctx_acc.credits.saturating_sub(ctx_acc.reserved_credits);
+                require!(available >= price, CustomError::InsufficientCredits);
+                ctx_acc.reserved_credits = ctx_acc
+                    .reserved_credits
+                    .checked_add(price)
+                    .ok_or(CustomError::MathOverflow)?;
+                c.tokens_due = 0;
+                c.reserved_credits = price;
+                if price > 0 {
+                    emit!(credits_changed(ctx_acc));
+                }
+            }
+    
+            // advance counter once per new PDA
+            ctx.accounts.context_account.next_chat_id =
+                ctx.accounts.context_account
+                    .next_chat_id
+                    .checked_add(1)
+                    .ok_or(CustomError::MathOverflow)?;
+        } else {
+            // if chat already exists, we don't do anything
+            require_eq!(c.id, chat_id, CustomError::ChatIdMismatch);
+            require_keys_eq!(c.context, ctx.accounts.context_account.key(), CustomError::ContextMismatch);
+            return Ok(());
+        }
+    
+        Ok(())
+    }
+
+    pub fn append_message(
+        ctx: Context<AppendMessage>,
+        index: u64,
+        content: Pubkey,
+        content_hash: [u8; 32],
+    ) -> Result<()> {
+        append(
+            &mut ctx.accounts.chat,
+            &mut ctx.accounts.message,
+            index,
+            MESSAGE_ROLE_USER,
+            content,
+            content_hash,
+        )
+    }
+
+    pub fn append_response(
+        ctx: Context<AppendResponse>,
+        index: u64,
+        content: Pubkey,
+        content_hash: [u8; 32],
+    ) -> Result<()> {
+        ctx.accounts.chat.oracle_seen_at = Clock::get()?.unix_timestamp;
+        append(
+            &mut ctx.accounts.chat,
+            &mut ctx.accounts.message,
+            index,
+            MESSAGE_ROLE_ASSISTANT,
+            content,
+            content_hash,
+        )
+    }
+
+    pub fn get_dek(ctx: Context<GetDek>, key_epoch: u32) -> Result<()> {
+        let caller_key = ctx.accounts.caller.key();
+        let c = &mut ctx.accounts.chat;
+    
+        // chat creator, the assigned oracle, or a reader on the access list
+        let is_user = caller_key == c.user;
+        let is_oracle = caller_key == c.assigned_oracle()
+            || (c.required_confirmations > 1 && ctx.accounts.config.is_oracle(&caller_key));
+        let is_reader =
+            access_role(&ctx.accounts.chat_access, &c.key(), &caller_key) >= ROLE_READER;
+        if !(is_user || is_oracle || is_reader) {
+            // last resort: a time-limited grant, which costs one use
+            let g = ctx
+                .accounts
+                .access_grant
+                .as_mut()
+                .ok_or(CustomError::Unauthorized)?;
+            require_keys_eq!(g.chat, c.key(), CustomError::Unauthorized);
+            require_keys_eq!(g.grantee, caller_key, CustomError::Unauthorized);
+            require!(
+                Clock::get()?.unix_timestamp < g.expires_at,
+                CustomError::GrantExpired
+            );
+            g.remaining_uses = g
+                .remaining_uses
+                .checked_sub(1)
+                .ok_or(CustomError::GrantExhausted)?;
+        }
+        require!(
+            !c.keys_revoked && !ctx.accounts.context_account.keys_revoked,
+            CustomError::KeysRevoked
+        );
+    
+        let cmk = epoch_cmk(c, &ctx.accounts.chat_key, key_epoch)?;
+        let dek = derive_dek(&cmk, &c.tx_id)?;
+
+        let clock = Clock::get()?;
+        c.record_dek_access(caller_key, clock.slot, clock.unix_timestamp)?;
+    
+        emit_cpi!(dek_response(c, key_epoch, None, caller_key, &clock, dek));
+    
+        Ok(())
+    }
+
+    pub fn get_message_dek(
+        ctx: Context<GetMessageDek>,
+        key_epoch: u32,
+        message_index: u64,
+    ) -> Result<()> {
+        let caller_key = ctx.accounts.caller.key();
+        let c = &mut ctx.accounts.chat;
+        let m = &ctx.accounts.message;
+
+        // only the message's author and the assigned oracle (any registered one
+        // on a consensus chat); shared readers stay on get_dek
+        let oracle = c.assigned_oracle();
+        let author = if m.role == MESSAGE_ROLE_USER { c.user } else { oracle };
+        let is_oracle = caller_key == oracle
+            || (c.required_confirmations > 1 && ctx.accounts.config.is_oracle(&caller_key));
+        require!(caller_key == author || is_oracle, CustomError::Unauthorized);
+        require!(
+            !c.keys_revoked && !ctx.accounts.context_account.keys_revoked,
+            CustomError::KeysRevoked
+        );
+
+        let cmk = epoch_cmk(c, &ctx.accounts.chat_key, key_epoch)?;
+        let dek = derive_message_dek(&cmk, c.id, message_index)?;
+
+        let clock = Clock::get()?;
+        c.record_dek_access(caller_key, clock.slot, clock.unix_timestamp)?;
+
+        emit_cpi!(dek_response(c, key_epoch, Some(message_index), caller_key, &clock, dek));
+
+        Ok(())
+    }
+
+    pub fn rotate_cmk(ctx: Context<RotateCmk>, new_cmk: Pubkey) -> Result<()> {
+        let c = &mut ctx.accounts.chat;
+
+        // archive the outgoing key so files sealed under it stay readable
+        let k = &mut ctx.accounts.chat_key;
+        k.chat = c.key();
+        k.epoch = c.key_epoch;
+        k.cmk = c.cmk;
+        k.retired_at = Clock::get()?.unix_timestamp;
+        k.version = CHAT_KEY_VERSION;
+
+        c.cmk = new_cmk;
+        c.key_epoch = c.key_epoch.checked_add(1).ok_or(CustomError::MathOverflow)?;
+        // a fresh key lifts a chat-level revocation
+        c.keys_revoked = false;
+
+        emit!(KeyRotated {
+            chat: c.key(),
+            chat_id: c.id,
+            key_epoch: c.key_epoch,
+        });
+        Ok(())
+    }
+
+    pub fn update_status(
+        ctx: Context<UpdateChatStatus>,
+        new_status: u8,                   // e.g. STATUS_DONE or STATUS_ERROR
+    ) -> Result<()> {
+        let caller_key = ctx.accounts.caller.key();
+        let c = &mut ctx.accounts.chat;
+        let is_user = caller_key == c.user;
+        let is_oracle = caller_key == c.assigned_oracle();
+        let is_writer =
+            access_role(&ctx.accounts.chat_access, &c.key(), &caller_key) >= ROLE_WRITER;
+        require!(is_user || is_oracle || is_writer, CustomError::Unauthorized);
+
+        require!(
+            new_status == STATUS_DONE
+                || new_status == STATUS_ERROR
+                || new_status == STATUS_PENDING
+                || new_status == STATUS_RUNNING,
+            CustomError::Unauthorized
+        );
+        // only the assigned oracle ends a chat; owners and writers use cancel_chat
+        require!(
+            is_oracle || !matches!(new_status, STATUS_DONE | STATUS_ERROR),
+            CustomError::Unauthorized
+        );
+        // DONE, ERROR and CANCELLED are final, so a settled chat can't be reopened
+        require!(
+            !matches!(c.status, STATUS_DONE | STATUS_ERROR | STATUS_CANCELLED),
+            CustomError::ChatNotActive
+        );
+        require!(
+            new_status != STATUS_DONE || c.callback.is_none(),
+            CustomError::CallbackRequiresFinalize
+        );
+        require!(
+            new_status != STATUS_DONE || ctx.accounts.config.challenge_window_secs == 0,
+            CustomError::ResultRequiresFinalize
+        );
+        require!(c.status != STATUS_SUBMITTED, CustomError::ResultPendingSettlement);
+        require!(
+            new_status != STATUS_DONE || c.required_confirmations <= 1,
+            CustomError::ConsensusRequired
+        );
+
+        let previous_status = c.status;
+        let clock = Clock::get()?;
+        c.set_status(new_status, &clock);
+        if is_oracle {
+            c.oracle_seen_at = clock.unix_timestamp;
+        }
+        // a bare status update carries no reason; see report_error
+        c.error_code = ChatErrorCode::None;
+        c.error_detail_hash = None;
+        settle_chat(&mut ctx.accounts.context_account, c)?;
+    
+        emit_cpi!(status_changed(c, previous_status, caller_key));
+        Ok(())
+    }
+
+    /// Marks the chat done with its result and, if the chat registered a
+    /// callback, invokes it signed by the CALLBACK_AUTHORITY_SEED PDA. Remaining
+    /// accounts are the callback program followed by its recorded accounts, in order.
+    pub fn finalize_chat<'info>(
+        ctx: Context<'_, '_, 'info, 'info, FinalizeChat<'info>>,
+        result_hash: [u8; 32],
+    ) -> Result<()> {
+        let c = &mut ctx.accounts.chat;
+        require!(
+            c.status == STATUS_PENDING || c.status == STATUS_RUNNING,
+            CustomError::ChatNotActive
+        );
+        require!(c.required_confirmations <= 1, CustomError::ConsensusRequired);
+
+        let previous_status = c.status;
+        c.result_hash = Some(result_hash);
+
+        let window = ctx.accounts.config.challenge_window_secs;
+        if window > 0 {
+            // optimistic: settle_result completes the chat once nobody objects
+            let clock = Clock::get()?;
+            c.set_status(STATUS_SUBMITTED, &clock);
+            c.challenge_ends_at = clock.unix_timestamp.saturating_add(window);
+            c.disputed = false;
+        } else {
+            complete_chat(
+                c,
+                &mut ctx.accounts.context_account,
+                &ctx.accounts.callback_authority,
+                ctx.bumps.callback_authority,
+                ctx.remaining_accounts,
+            )?;
+        }
+
+        emit_cpi!(status_changed(c, previous_status, ctx.accounts.oracle.key()));
+        Ok(())
+    }
+
+    /// Switches an untouched chat to k-of-n mode: it completes only once
+    /// `required` distinct registered oracles submit the same result hash.
+    pub fn set_consensus(ctx: Context<SetConsensus>, required: u8) -> Result<()> {
+        let c = &mut ctx.accounts.chat;
+        require!(
+            matches!(c.status, STATUS_WAITING_FOR_DELEGATION | STATUS_PENDING)
+                && c.result_hash.is_none(),
+            CustomError::ChatNotActive
+        );
+        // more confirmations than registered oracles could never be reached
+        require!(
+            required >= 2 && required as usize <= ctx.accounts.config.oracles.len(),
+            CustomError::InvalidConfirmations
+        );
+        c.required_confirmations = required;
+
+        let s = &mut ctx.accounts.submissions;
+        s.chat = c.key();
+        s.agreed_hash = None;
+        s.version = SUBMISSIONS_VERSION;
+        Ok(())
+    }
+
+    /// One registered oracle's answer on a consensus chat. The k-th matching
+    /// hash is treated like finalize_chat; every submission that disagrees
+    /// with it stays on record for disputes and slashing.
+    pub fn submit_result<'info>(
+        ctx: Context<'_, '_, 'info, 'info, SubmitResult<'info>>,
+        result_hash: [u8; 32],
+    ) -> Result<()> {
+        let oracle = ctx.accounts.oracle.key();
+        require!(ctx.accounts.config.is_oracle(&oracle), CustomError::OracleNotRegistered);
+
+        let c = &mut ctx.accounts.chat;
+        require!(c.required_confirmations > 1, CustomError::NotConsensusChat);
+        require!(
+            c.status == STATUS_PENDING || c.status == STATUS_RUNNING,
+            CustomError::ChatNotActive
+        );
+
+        let clock = Clock::get()?;
+        let s = &mut ctx.accounts.submissions;
+        require!(
+            s.submissions.iter().all(|x| x.oracle != oracle),
+            CustomError::DuplicateSubmission
+        );
+        require!(s.submissions.len() < ORACLES_MAX, CustomError::TooManySubmissions);
+        s.submissions.push(Submission {
+            oracle,
+            result_hash,
+            submitted_at: clock.unix_timestamp,
+        });
+        let matches = s
+            .submissions
+            .iter()
+            .filter(|x| x.result_hash == result_hash)
+            .count() as u8;
+
+        emit!(ResultSubmitted {
+            chat: c.key(),
+            oracle,
+            result_hash,
+            matches,
+        });
+        if matches < c.required_confirmations {
+            return Ok(());
+        }
+
+        s.agreed_hash = Some(result_hash);
+        for x in s.submissions.iter().filter(|x| x.result_hash != result_hash) {
+            emit!(ResultMismatch {
+                chat: c.key(),
+                oracle: x.oracle,
+                result_hash: x.result_hash,
+                agreed_hash: result_hash,
+            });
+        }
+
+        let previous_status = c.status;
+        c.result_hash = Some(result_hash);
+        let window = ctx.accounts.config.challenge_window_secs;
+        if window > 0 {
+            c.set_status(STATUS_SUBMITTED, &clock);
+            c.challenge_ends_at = clock.unix_timestamp.saturating_add(window);
+            c.disputed = false;
+        } else {
+            complete_chat(
+                c,
+                &mut ctx.accounts.context_account,
+                &ctx.accounts.callback_authority,
+                ctx.bumps.callback_authority,
+                ctx.remaining_accounts,
+            )?;
+        }
+
+        emit_cpi!(status_changed(c, previous_status, oracle));
+        Ok(())
+    }
+
+    /// Permissionless: completes a submitted chat once its challenge window
+    /// has closed without an open dispute.
+    pub fn settle_result<'info>(
+        ctx: Context<'_, '_, 'info, 'info, SettleResult<'info>>,
+    ) -> Result<()> {
+        let c = &mut ctx.accounts.chat;
+        require!(c.status == STATUS_SUBMITTED, CustomError::ChatNotActive);
+        require!(!c.disputed, CustomError::DisputeOpen);
+        require!(
+            Clock::get()?.unix_timestamp >= c.challenge_ends_at,
+            CustomError::ChallengeWindowOpen
+        );
+
+        complete_chat(
+            c,
+            &mut ctx.accounts.context_account,
+            &ctx.accounts.callback_authority,
+            ctx.bumps.callback_authority,
+            ctx.remaining_accounts,
+        )?;
+
+        emit_cpi!(status_changed(c, STATUS_SUBMITTED, ctx.accounts.caller.key()));
+        Ok(())
+    }
+
+    /// Way out for a submitted result that can't settle, e.g. because the
+    /// consumer callback keeps reverting: ends the chat in ERROR without the
+    /// callback and releases its credits. The assigned oracle may do this once
+    /// the window has closed, the owner only after a further oracle deadline so
+    /// a working result is settled rather than abandoned.
+    pub fn abandon_result(ctx: Context<AbandonResult>) -> Result<()> {
+        let caller = ctx.accounts.caller.key();
+        let c = &mut ctx.accounts.chat;
+        require!(c.status == STATUS_SUBMITTED, CustomError::ChatNotActive);
+        require!(!c.disputed, CustomError::DisputeOpen);
+
+        let opens_at = if caller == c.assigned_oracle() {
+            c.challenge_ends_at
+        } else {
+            require_keys_eq!(caller, c.user, CustomError::Unauthorized);
+            c.challenge_ends_at
+                .saturating_add(ctx.accounts.config.oracle_timeout_secs())
+        };
+        let clock = Clock::get()?;
+        require!(clock.unix_timestamp >= opens_at, CustomError::ChallengeWindowOpen);
+
+        c.set_status(STATUS_ERROR, &clock);
+        c.error_code = ChatErrorCode::SettlementFailed;
+        c.error_detail_hash = None;
+        settle_chat(&mut ctx.accounts.context_account, c)?;
+
+        emit_cpi!(status_changed(c, STATUS_SUBMITTED, caller));
+        Ok(())
+    }
+
+    /// The chat owner or any watcher contests a submitted result, locking
+    /// `dispute_bond` lamports until the arbiter rules.
+    pub fn open_dispute(ctx: Context<OpenDispute>, evidence_hash: [u8; 32]) -> Result<()> {
+        let c = &mut ctx.accounts.chat;
+        require!(c.status == STATUS_SUBMITTED, CustomError::ChatNotActive);
+        require!(
+            Clock::get()?.unix_timestamp < c.challenge_ends_at,
+            CustomError::ChallengeWindowClosed
+        );
+
+        let bond = ctx.accounts.config.dispute_bond;
+        system_program::transfer(
+            CpiContext::new(
+                ctx.accounts.system_program.to_account_info(),
+                system_program::Transfer {
+                    from: ctx.accounts.challenger.to_account_info(),
+                    to: ctx.accounts.dispute.to_account_info(),
+                },
+            ),
+            bond,
+        )?;
+
+        let d = &mut ctx.accounts.dispute;
+        d.chat = c.key();
+        d.challenger = ctx.accounts.challenger.key();
+        d.bond = bond;
+        d.evidence_hash = evidence_hash;
+        d.opened_at = Clock::get()?.unix_timestamp;
+        d.version = DISPUTE_VERSION;
+        c.disputed = true;
+
+        emit!(DisputeOpened {
+            chat: c.key(),
+            challenger: d.challenger,
+            bond,
+            evidence_hash,
+        });
+        Ok(())
+    }
+
+    /// Upheld: the result is thrown out, the user's credits are released and
+    /// the challenger gets their bond back plus the oracle's slash. Rejected:
+    /// the oracle keeps the challenger's bond and the chat settles as usual
+    /// once the window closes. On a consensus chat "the oracle" is every
+    /// submitter that agreed on the result, passed as remaining accounts.
+    pub fn resolve_dispute<'info>(
+        ctx: Context<'_, '_, 'info, 'info, ResolveDispute<'info>>,
+        upheld: bool,
+    ) -> Result<()> {
+        let chat_key = ctx.accounts.chat.key();
+        let d = &ctx.accounts.dispute;
+        let submitters = if ctx.accounts.chat.required_confirmations > 1 {
+            let s = ctx.accounts.submissions.as_ref().ok_or(CustomError::SubmitterMismatch)?;
+            Some(agreeing_submitters(s, ctx.remaining_accounts)?)
+        } else {
+            None
+        };
+
+        if upheld {
+            let amount = ctx.accounts.config.dispute_slash;
+            let bonds: Vec<&AccountInfo> = match &submitters {
+                Some(submitters) => submitters.iter().map(|(_, bond)| *bond).collect(),
+                None => vec![ctx.accounts.bond.as_ref()],
+            };
+            for bond_info in bonds {
+                slash_if_staked(
+                    &mut ctx.accounts.config,
+                    bond_info,
+                    &ctx.accounts.challenger,
+                    chat_key,
+                    SlashReason::LostDispute,
+                    amount,
+                )?;
+            }
+
+            let c = &mut ctx.accounts.chat;
+            c.set_status(STATUS_ERROR, &Clock::get()?);
+            c.error_code = ChatErrorCode::ResultDisputed;
+            c.error_detail_hash = Some(d.evidence_hash);
+            c.disputed = false;
+            settle_chat(&mut ctx.accounts.context_account, c)?;
+            emit_cpi!(status_changed(c, STATUS_SUBMITTED, ctx.accounts.arbiter.key()));
+        } else {
+            // forfeit the bond to the oracle; the rent goes back to the challenger on close
+            let dispute_info = d.to_account_info();
+            **dispute_info.try_borrow_mut_lamports()? -= d.bond;
+            match &submitters {
+                Some(submitters) => {
+                    // split evenly, the first submitter takes the remainder
+                    let n = submitters.len() as u64;
+                    for (i, (oracle, _)) in submitters.iter().enumerate() {
+                        let share = d.bond / n + if i == 0 { d.bond % n } else { 0 };
+                        **oracle.try_borrow_mut_lamports()? += share;
+                    }
+                }
+                None => **ctx.accounts.oracle.try_borrow_mut_lamports()? += d.bond,
+            }
+            ctx.accounts.chat.disputed = false;
+        }
+
+        emit!(DisputeResolved {
+            chat: chat_key,
+            challenger: d.challenger,
+            upheld,
+        });
+        Ok(())
+    }
+
+    /// Cheap enough to call often inside the ephemeral rollup: it only touches
+    /// the (delegated) chat, so no commit or undelegation is needed.
+    pub fn report_progress(ctx: Context<ReportProgress>, percent: u8, stage: u8) -> Result<()> {
+        require!(percent <= 100, CustomError::InvalidProgress);
+        let c = &mut ctx.accounts.chat;
+        require!(
+            c.status == STATUS_PENDING || c.status == STATUS_RUNNING,
+            CustomError::ChatNotActive
+        );
+
+        let clock = Clock::get()?;
+        c.progress_percent = percent;
+        c.progress_stage = stage;
+        c.oracle_seen_at = clock.unix_timestamp;
+        if c.status == STATUS_PENDING {
+            c.set_status(STATUS_RUNNING, &clock);
+            emit_cpi!(status_changed(c, STATUS_PENDING, ctx.accounts.oracle.key()));
+        }
+
+        emit!(Progress {
+            chat: c.key(),
+            chat_id: c.id,
+            percent,
+            stage,
+        });
+        Ok(())
+    }
+
+    pub fn report_error(
+        ctx: Context<ReportError>,
+        error_code: ChatErrorCode,
+        detail_hash: Option<[u8; 32]>,
+    ) -> Result<()> {
+        let c = &mut ctx.accounts.chat;
+        require!(
+            c.status == STATUS_PENDING || c.status == STATUS_RUNNING,
+            CustomError::ChatNotActive
+        );
+        let previous_status = c.status;
+        c.set_status(STATUS_ERROR, &Clock::get()?);
+        c.error_code = error_code;
+        c.error_detail_hash = detail_hash;
+        settle_chat(&mut ctx.accounts.context_account, c)?;
+
+        emit_cpi!(status_changed(c, previous_status, ctx.accounts.oracle.key()));
+        Ok(())
+    }
+
+    pub fn revoke_chat_keys(ctx: Context<ManageChatKeys>) -> Result<()> {
+        let c = &mut ctx.accounts.chat;
+        c.keys_revoked = true;
+
+        emit!(KeysRevoked {
+            context: c.context,
+            chat: Some(c.key()),
+        });
+        Ok(())
+    }
+
+    pub fn restore_chat_keys(ctx: Context<ManageChatKeys>) -> Result<()> {
+        ctx.accounts.chat.keys_revoked = false;
+        Ok(())
+    }
+
+    pub fn create_chat_access(ctx: Context<CreateChatAccess>) -> Result<()> {
+        let a = &mut ctx.accounts.chat_access;
+        a.chat = ctx.accounts.chat.key();
+        a.version = CHAT_ACCESS_VERSION;
+        Ok(())
+    }
+
+    pub fn grant_access(ctx: Context<GrantAccess>, member: Pubkey, role: u8) -> Result<()> {
+        require!(
+            role == ROLE_READER || role == ROLE_WRITER,
+            CustomError::InvalidRole
+        );
+        let a = &mut ctx.accounts.chat_access;
+
+        // the account was already resized for a new entry by the realloc constraint
+        match a.members.iter_mut().find(|m| m.member == member) {
+            Some(m) => m.role = role,
+            None => a.members.push(ChatMember { member, role }),
+        }
+
+        emit!(AccessChanged {
+            chat: a.chat,
+            member,
+            role,
+        });
+        Ok(())
+    }
+
+    pub fn revoke_access(ctx: Context<RevokeAccess>, member: Pubkey) -> Result<()> {
+        let a = &mut ctx.accounts.chat_access;
+        let idx = a
+            .members
+            .iter()
+            .position(|m| m.member == member)
+            .ok_or(CustomError::MemberNotFound)?;
+        a.members.swap_remove(idx);
+
+        emit!(AccessChanged {
+            chat: a.chat,
+            member,
+            role: ROLE_NONE,
+        });
+        Ok(())
+    }
+
+    pub fn create_access_grant(
+        ctx: Context<CreateAccessGrant>,
+        grantee: Pubkey,
+        expires_at: i64,
+        max_uses: u32,
+    ) -> Result<()> {
+        require!(
+            expires_at > Clock::get()?.unix_timestamp && max_uses > 0,
+            CustomError::InvalidGrant
+        );
+
+        let g = &mut ctx.accounts.access_grant;
+        g.chat = ctx.accounts.chat.key();
+        g.grantee = grantee;
+        g.expires_at = expires_at;
+        g.max_uses = max_uses;
+        g.remaining_uses = max_uses;
+        g.version = ACCESS_GRANT_VERSION;
+        Ok(())
+    }
+
+    pub fn close_access_grant(_ctx: Context<CloseAccessGrant>) -> Result<()> {
+        Ok(())
+    }
+
+    pub fn set_context_keys_revoked(
+        ctx: Context<ManageContextKeys>,
+        revoked: bool,
+    ) -> Result<()> {
+        let c = &mut ctx.accounts.context_account;
+        c.keys_revoked = revoked;
+
+        if revoked {
+            emit!(KeysRevoked {
+                context: c.key(),
+                chat: None,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn set_context_master_key(
+        ctx: Context<ManageContextKeys>,
+        master_key: Option<Pubkey>,
+    ) -> Result<()> {
+        // only affects chats created afterwards; existing chats keep their cmk
+        ctx.accounts.context_account.master_key = master_key;
+        Ok(())
+    }
+
+    pub fn set_context_limits(
+        ctx: Context<ManageContextLimits>,
+        limits: ContextLimits,
+    ) -> Result<()> {
+        require!(
+            limits.max_chats_per_window == 0
+                || (limits.window_secs > 0
+                    && limits.max_chats_per_window as usize <= CHAT_RATE_LOG_LEN),
+            CustomError::InvalidLimits
+        );
+        ctx.accounts.context_account.limits = limits;
+        Ok(())
+    }
+
+    pub fn migrate_context(ctx: Context<MigrateContext>) -> Result<()> {
+        let owner = ctx.accounts.owner.key();
+        migrate_layout::<ContextAccount>(
+            &ctx.accounts.context_account,
+            &ctx.accounts.owner,
+            Some(&ctx.accounts.owner),
+            &ctx.accounts.system_program,
+            8 + ContextAccount::INIT_SPACE,
+            |c| {
+                require_keys_eq!(c.owner, owner, CustomError::ContextOwnerMismatch);
+                c.version = CONTEXT_VERSION;
+                Ok(())
+            },
+        )
+    }
+
+    pub fn migrate_chat(ctx: Context<MigrateChat>) -> Result<()> {
+        migrate_layout::<Chat>(
+            &ctx.accounts.chat,
+            &ctx.accounts.payer,
+            None, // permissionless: surplus lamports stay with the chat
+            &ctx.accounts.system_program,
+            8 + Chat::INIT_SPACE,
+            |c| {
+                c.version = CHAT_VERSION;
+                Ok(())
+            },
+        )
+    }
+
+    pub fn cancel_chat(ctx: Context<CancelChat>) -> Result<()> {
+        let c = &mut ctx.accounts.chat;
+        // DONE, ERROR and CANCELLED are final
+        require!(
+            !matches!(c.status, STATUS_DONE | STATUS_ERROR | STATUS_CANCELLED),
+            CustomError::CannotCancel
+        );
+        require!(c.status != STATUS_SUBMITTED, CustomError::ResultPendingSettlement);
+        let previous_status = c.status;
+        c.set_status(STATUS_CANCELLED, &Clock::get()?);
+        settle_chat(&mut ctx.accounts.context_account, c)?;
+
+        emit_cpi!(status_changed(c, previous_status, ctx.accounts.user.key()));
+        emit!(ChatCancelled {
+            chat: c.key(),
+            chat_id: c.id,
+            previous_status,
+        });
+        Ok(())
+    }
+
+    /// Moves an open chat to another registered oracle. The admin may do this
+    /// at any time; the chat owner once the assignee is deregistered or has
+    /// not shown a sign of life within the configured deadline.
+    pub fn reassign_oracle(ctx: Context<ReassignOracle>, oracle: Option<Pubkey>) -> Result<()> {
+        let caller_key = ctx.accounts.caller.key();
+        let config = &mut ctx.accounts.config;
+        let c = &mut ctx.accounts.chat;
+        require!(
+            matches!(
+                c.status,
+                STATUS_WAITING_FOR_DELEGATION | STATUS_PENDING | STATUS_RUNNING
+            ),
+            CustomError::ChatNotActive
+        );
+
+        let clock = Clock::get()?;
+        let previous = c.assigned_oracle();
+        if caller_key != config.admin {
+            require_keys_eq!(caller_key, c.user, CustomError::Unauthorized);
+            require!(
+                !config.is_oracle(&previous)
+                    || c.oracle_idle_secs(clock.unix_timestamp) >= config.oracle_timeout_secs(),
+                CustomError::OracleNotStale
+            );
+        }
+
+        let mut next = config.pick_oracle(oracle)?;
+        if next == previous && oracle.is_none() {
+            // round-robin landed on the stale oracle; skip it once
+            next = config.pick_oracle(None)?;
+        }
+        require_keys_neq!(next, previous, CustomError::OracleUnchanged);
+        c.oracle = next;
+        c.oracle_slashed = false;
+        c.oracle_seen_at = clock.unix_timestamp; // the new assignee's deadline starts now
+
+        // the new oracle starts the inference over
+        let previous_status = c.status;
+        if previous_status == STATUS_RUNNING {
+            c.set_status(STATUS_PENDING, &clock);
+            c.progress_percent = 0;
+            c.progress_stage = 0;
+            emit_cpi!(status_changed(c, previous_status, caller_key));
+        }
+        emit!(OracleAssigned {
+            chat: c.key(),
+            oracle: next,
+            previous: Some(previous),
+        });
+        Ok(())
+    }
+
+    pub fn delegate_chat(ctx: Context<DelegateChat>, chat_id: u64) -> Result<()> {
+        ctx.accounts.delegate_chat(
+            &ctx.accounts.payer,
+            &[
+                CHAT_SEED,
+                ctx.accounts.context_account.key().as_ref(),
+                &chat_id.to_le_bytes(),
+            ],
+            DelegateConfig {
+                commit_frequency_ms: 0,
+                validator: Some(pubkey!("mAGicPQYBMvcYveUZA5F5UNNwyHvfYh5xkLS2Fr1mev")),
+            },
+        )?;
+        Ok(())
+    }
+}
+
+/// Contexts
+
+#[derive(Accounts)]
+pub struct Initialize<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = 8,
+        seeds = [b"identity"],
+        bump
+    )]
+    pub identity: Account<'info, Identity>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct InitConfig<'info> {
+    #[account(
+        mut,
+        address = ORACLE_IDENTITY @ CustomError::Unauthorized
+    )]
+    pub admin: Signer<'info>,
+
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + Config::INIT_SPACE,
+        seeds = [CONFIG_SEED],
+        bump
+    )]
+    pub config: Account<'info, Config>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct AdminOnly<'info> {
+    pub admin: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [CONFIG_SEED],
+        bump,
+        has_one = admin @ CustomError::Unauthorized
+    )]
+    pub config: Account<'info, Config>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateConfig<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    /// CHECK: may still hold an older layout; re-read and admin-checked in the handler
+    #[account(mut, owner = crate::ID, seeds = [CONFIG_SEED], bump)]
+    pub config: UncheckedAccount<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawDeposits<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    #[account(
+        seeds = [CONFIG_SEED],
+        bump,
+        has_one = admin @ CustomError::Unauthorized
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(mut, seeds = [DEPOSIT_PDA_SEED], bump)]
+    pub deposit: SystemAccount<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct BuyCredits<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = context_account.owner == owner.key() @ CustomError::ContextOwnerMismatch
+    )]
+    pub context_account: Account<'info, ContextAccount>,
+
+    #[account(seeds = [CONFIG_SEED], bump)]
+    pub config: Account<'info, Config>,
+
+    /// Program-wide treasury for credit sales.
+    #[account(mut, seeds = [DEPOSIT_PDA_SEED], bump)]
+    pub deposit: SystemAccount<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct DepositTokens<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    #[account(
+        seeds = [CONFIG_SEED],
+        bump,
+        constraint = config.allowed_mint(&mint.key()).is_some() @ CustomError::MintNotAllowed
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(mint::token_program = token_program)]
+    pub mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        mut,
+        token::mint = mint,
+        token::authority = owner,
+        token::token_program = token_program
+    )]
+    pub source: InterfaceAccount<'info, TokenAccount>,
+
+    /// Per-owner escrow for `mint`, held by the program's token authority.
+    #[account(
+        init_if_needed,
+        payer = owner,
+        seeds = [TOKEN_VAULT_SEED, owner.key().as_ref(), mint.key().as_ref()],
+        bump,
+        token::mint = mint,
+        token::authority = token_authority,
+        token::token_program = token_program
+    )]
+    pub vault: InterfaceAccount<'info, TokenAccount>,
+
+    /// CHECK: PDA signer for every vault and treasury token account
+    #[account(seeds = [TOKEN_AUTHORITY_SEED], bump)]
+    pub token_authority: UncheckedAccount<'info>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawTokens<'info> {
+    pub owner: Signer<'info>,
+
+    #[account(mint::token_program = token_program)]
+    pub mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        mut,
+        seeds = [TOKEN_VAULT_SEED, owner.key().as_ref(), mint.key().as_ref()],
+        bump,
+        token::mint = mint,
+        token::token_program = token_program
+    )]
+    pub vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        token::mint = mint,
+        token::token_program = token_program
+    )]
+    pub destination: InterfaceAccount<'info, TokenAccount>,
+
+    /// CHECK: PDA signer for every vault and treasury token account
+    #[account(seeds = [TOKEN_AUTHORITY_SEED], bump)]
+    pub token_authority: UncheckedAccount<'info>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct ChargeTokens<'info> {
+    #[account(mut)]
+    pub oracle: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = chat.assigned_oracle() == oracle.key() @ CustomError::Unauthorized
+    )]
+    pub chat: Account<'info, Chat>,
+
+    /// The mint the chat was created to be paid in.
+    #[account(
+        mint::token_program = token_program,
+        constraint = chat.payment_mint == Some(mint.key()) @ CustomError::PaymentMintMismatch
+    )]
+    pub mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        mut,
+        seeds = [TOKEN_VAULT_SEED, chat.user.as_ref(), mint.key().as_ref()],
+        bump,
+        token::mint = mint,
+        token::token_program = token_program
+    )]
+    pub vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        init_if_needed,
+        payer = oracle,
+        seeds = [TOKEN_TREASURY_SEED, mint.key().as_ref()],
+        bump,
+        token::mint = mint,
+        token::authority = token_authority,
+        token::token_program = token_program
+    )]
+    pub treasury: InterfaceAccount<'info, TokenAccount>,
+
+    /// CHECK: PDA signer for every vault and treasury token account
+    #[account(seeds = [TOKEN_AUTHORITY_SEED], bump)]
+    pub token_authority: UncheckedAccount<'info>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawTokenTreasury<'info> {
+    pub admin: Signer<'info>,
+
+    #[account(
+        seeds = [CONFIG_SEED],
+        bump,
+        has_one = admin @ CustomError::Unauthorized
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(mint::token_program = token_program)]
+    pub mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        mut,
+        seeds = [TOKEN_TREASURY_SEED, mint.key().as_ref()],
+        bump,
+        token::mint = mint,
+        token::token_program = token_program
+    )]
+    pub treasury: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        token::mint = mint,
+        token::token_program = token_program
+    )]
+    pub destination: InterfaceAccount<'info, TokenAccount>,
+
+    /// CHECK: PDA signer for every vault and treasury token account
+    #[account(seeds = [TOKEN_AUTHORITY_SEED], bump)]
+    pub token_authority: UncheckedAccount<'info>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct Stake<'info> {
+    #[account(mut)]
+    pub operator: Signer<'info>,
+
+    #[account(mut, seeds = [CONFIG_SEED], bump)]
+    pub config: Account<'info, Config>,
+
+    #[account(
+        init_if_needed,
+        payer = operator,
+        space = 8 + OracleBond::INIT_SPACE,
+        seeds = [ORACLE_BOND_SEED, operator.key().as_ref()],
+        bump
+    )]
+    pub bond: Account<'info, OracleBond>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ManageStake<'info> {
+    #[account(mut)]
+    pub operator: Signer<'info>,
+
+    #[account(mut, seeds = [CONFIG_SEED], bump)]
+    pub config: Account<'info, Config>,
+
+    #[account(
+        mut,
+        seeds = [ORACLE_BOND_SEED, operator.key().as_ref()],
+        bump
+    )]
+    pub bond: Account<'info, OracleBond>,
+}
+
+#[derive(Accounts)]
+pub struct SlashOracle<'info> {
+    pub reporter: Signer<'info>,
+
+    #[account(mut, seeds = [CONFIG_SEED], bump)]
+    pub config: Account<'info, Config>,
+
+    #[account(mut)]
+    pub chat: Account<'info, Chat>,
+
+    /// Its kill switch excuses the oracle as well.
+    #[account(
+        constraint = context_account.key() == chat.context @ CustomError::ContextMismatch
+    )]
+    pub context_account: Account<'info, ContextAccount>,
+
+    /// Bond of the chat's assigned oracle.
+    #[account(
+        mut,
+        seeds = [ORACLE_BOND_SEED, chat.assigned_oracle().as_ref()],
+        bump
+    )]
+    pub bond: Account<'info, OracleBond>,
+
+    /// Slashed lamports go to the program treasury.
+    #[account(mut, seeds = [DEPOSIT_PDA_SEED], bump)]
+    pub deposit: SystemAccount<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(model_id: u32)]
+pub struct RegisterModel<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    #[account(
+        seeds = [CONFIG_SEED],
+        bump,
+        has_one = admin @ CustomError::Unauthorized
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + Model::INIT_SPACE,
+        seeds = [MODEL_SEED, &model_id.to_le_bytes()],
+        bump
+    )]
+    pub model: Account<'info, Model>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateModel<'info> {
+    pub admin: Signer<'info>,
+
+    #[account(
+        seeds = [CONFIG_SEED],
+        bump,
+        has_one = admin @ CustomError::Unauthorized
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(mut)]
+    pub model: Account<'info, Model>,
+}
+
+#[derive(Accounts)]
+pub struct CreateContext<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    /// Wallet or program PDA (signing via invoke_signed) that owns the context;
+    /// wallets simply pass themselves as both payer and owner.
+    pub owner: Signer<'info>,
+
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = 8 + ContextAccount::INIT_SPACE,
+        seeds = [ContextAccount::seed(), owner.key().as_ref()],
+        bump
+    )]
+    pub context_account: Account<'info, ContextAccount>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(chat_id: u64)]
+pub struct CreateChat<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    /// Context owner, becomes `chat.user`; may be a program PDA.
+    pub owner: Signer<'info>,
+
+    // MUST be the owner; ensures "context uniquely connected to the user"
+    #[account(
+        mut,
+        constraint = context_account.owner == owner.key() @ CustomError::ContextOwnerMismatch
+    )]
+    pub context_account: Account<'info, ContextAccount>,
+
+    /// creates the interaction PDA if needed
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = 8 + Chat::INIT_SPACE,
+        seeds = [
+            CHAT_SEED,
+            context_account.key().as_ref(),
+            &chat_id.to_le_bytes(),
+        ],
+        bump
+    )]
+    pub chat: Account<'info, Chat>,
+
+    /// Must be an enabled registry entry.
+    #[account(
+        seeds = [MODEL_SEED, &model.model_id.to_le_bytes()],
+        bump,
+        constraint = model.enabled @ CustomError::ModelDisabled
+    )]
+    pub model: Account<'info, Model>,
+
+    #[account(
+        seeds = [CONFIG_SEED],
+        bump,
+        mut,
+        constraint = !config.is_paused(PAUSE_CREATE_CHAT) @ CustomError::Paused
+    )]
+    pub config: Account<'info, Config>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(index: u64)]
+pub struct AppendMessage<'info> {
+    /// Only the chat creator writes user turns.
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = chat.user == user.key() @ CustomError::Unauthorized
+    )]
+    pub chat: Account<'info, Chat>,
+
+    #[account(
+        init,
+        payer = user,
+        space = 8 + Message::INIT_SPACE,
+        seeds = [MESSAGE_SEED, chat.key().as_ref(), &index.to_le_bytes()],
+        bump
+    )]
+    pub message: Account<'info, Message>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(index: u64)]
+pub struct AppendResponse<'info> {
+    /// Only the chat's assigned oracle writes assistant turns.
+    #[account(mut)]
+    pub oracle: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = chat.assigned_oracle() == oracle.key() @ CustomError::Unauthorized
+    )]
+    pub chat: Account<'info, Chat>,
+
+    #[account(
+        init,
+        payer = oracle,
+        space = 8 + Message::INIT_SPACE,
+        seeds = [MESSAGE_SEED, chat.key().as_ref(), &index.to_le_bytes()],
+        bump
+    )]
+    pub message: Account<'info, Message>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct GetDek<'info> {
+    /// chat.user, the oracle identity, or a reader on the access list.
+    #[account(mut)]
+    pub caller: Signer<'info>,
+
+    /// Must be owned by this program.
+    #[account(mut)]
+    pub chat: Account<'info, Chat>,
+
+    /// Carries the context-wide kill switch.
+    #[account(
+        constraint = context_account.key() == chat.context @ CustomError::ContextMismatch
+    )]
+    pub context_account: Account<'info, ContextAccount>,
+
+    /// Archived key, required only when asking for a past epoch.
+    pub chat_key: Option<Account<'info, ChatKey>>,
+
+    /// Access list, required only when the caller is a shared reader.
+    pub chat_access: Option<Account<'info, ChatAccess>>,
+
+    /// Time-limited grant, required only when the caller relies on one.
+    #[account(mut)]
+    pub access_grant: Option<Account<'info, AccessGrant>>,
+
+    #[account(
+        seeds = [CONFIG_SEED],
+        bump,
+        constraint = !config.is_paused(PAUSE_GET_DEK) @ CustomError::Paused
+    )]
+    pub config: Account<'info, Config>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+#[instruction(key_epoch: u32, message_index: u64)]
+pub struct GetMessageDek<'info> {
+    /// The message's author or the oracle identity.
+    #[account(mut)]
+    pub caller: Signer<'info>,
+
+    #[account(mut)]
+    pub chat: Account<'info, Chat>,
+
+    #[account(
+        seeds = [MESSAGE_SEED, chat.key().as_ref(), &message_index.to_le_bytes()],
+        bump
+    )]
+    pub message: Account<'info, Message>,
+
+    /// Carries the context-wide kill switch.
+    #[account(
+        constraint = context_account.key() == chat.context @ CustomError::ContextMismatch
+    )]
+    pub context_account: Account<'info, ContextAccount>,
+
+    /// Archived key, required only when asking for a past epoch.
+    pub chat_key: Option<Account<'info, ChatKey>>,
+
+    #[account(
+        seeds = [CONFIG_SEED],
+        bump,
+        constraint = !config.is_paused(PAUSE_GET_DEK) @ CustomError::Paused
+    )]
+    pub config: Account<'info, Config>,
+}
+
+#[derive(Accounts)]
+pub struct RotateCmk<'info> {
+    /// Only the chat creator may rotate.
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = chat.user == user.key() @ CustomError::Unauthorized
+    )]
+    pub chat: Account<'info, Chat>,
+
+    /// archive of the key being retired
+    #[account(
+        init,
+        payer = user,
+        space = 8 + ChatKey::INIT_SPACE,
+        seeds = [
+            CHAT_KEY_SEED,
+            chat.key().as_ref(),
+            &chat.key_epoch.to_le_bytes(),
+        ],
+        bump
+    )]
+    pub chat_key: Account<'info, ChatKey>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ManageChatKeys<'info> {
+    /// Only the chat creator.
+    pub user: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = chat.user == user.key() @ CustomError::Unauthorized
+    )]
+    pub chat: Account<'info, Chat>,
+}
+
+#[derive(Accounts)]
+pub struct CreateChatAccess<'info> {
+    /// Only the chat creator manages the access list.
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(
+        constraint = chat.user == user.key() @ CustomError::Unauthorized
+    )]
+    pub chat: Account<'info, Chat>,
+
+    #[account(
+        init,
+        payer = user,
+        space = ChatAccess::space(0),
+        seeds = [CHAT_ACCESS_SEED, chat.key().as_ref()],
+        bump
+    )]
+    pub chat_access: Account<'info, ChatAccess>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(member: Pubkey)]
+pub struct GrantAccess<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(
+        constraint = chat.user == user.key() @ CustomError::Unauthorized
+    )]
+    pub chat: Account<'info, Chat>,
+
+    /// grows by one entry when `member` is new
+    #[account(
+        mut,
+        seeds = [CHAT_ACCESS_SEED, chat.key().as_ref()],
+        bump,
+        realloc = ChatAccess::space(
+            chat_access.members.len() + usize::from(chat_access.role_of(&member) == ROLE_NONE)
+        ),
+        realloc::payer = user,
+        realloc::zero = false
+    )]
+    pub chat_access: Account<'info, ChatAccess>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RevokeAccess<'info> {
+    /// Receives the rent freed by the removed entry.
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(
+        constraint = chat.user == user.key() @ CustomError::Unauthorized
+    )]
+    pub chat: Account<'info, Chat>,
+
+    #[account(
+        mut,
+        seeds = [CHAT_ACCESS_SEED, chat.key().as_ref()],
+        bump,
+        realloc = ChatAccess::space(chat_access.members.len().saturating_sub(1)),
+        realloc::payer = user,
+        realloc::zero = false
+    )]
+    pub chat_access: Account<'info, ChatAccess>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(grantee: Pubkey)]
+pub struct CreateAccessGrant<'info> {
+    /// Only the chat creator hands out grants.
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(
+        constraint = chat.user == user.key() @ CustomError::Unauthorized
+    )]
+    pub chat: Account<'info, Chat>,
+
+    #[account(
+        init,
+        payer = user,
+        space = 8 + AccessGrant::INIT_SPACE,
+        seeds = [ACCESS_GRANT_SEED, chat.key().as_ref(), grantee.as_ref()],
+        bump
+    )]
+    pub access_grant: Account<'info, AccessGrant>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CloseAccessGrant<'info> {
+    /// Chat creator; receives the rent refund.
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(
+        constraint = chat.user == user.key() @ CustomError::Unauthorized
+    )]
+    pub chat: Account<'info, Chat>,
+
+    #[account(
+        mut,
+        close = user,
+        seeds = [ACCESS_GRANT_SEED, chat.key().as_ref(), access_grant.grantee.as_ref()],
+        bump
+    )]
+    pub access_grant: Account<'info, AccessGrant>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct CancelChat<'info> {
+    /// Only the chat creator.
+    pub user: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = chat.user == user.key() @ CustomError::Unauthorized
+    )]
+    pub chat: Account<'info, Chat>,
+
+    /// Holds the credit reservation settled by this transition.
+    #[account(
+        mut,
+        constraint = context_account.key() == chat.context @ CustomError::ContextMismatch
+    )]
+    pub context_account: Account<'info, ContextAccount>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct ReassignOracle<'info> {
+    /// The config admin, or the chat creator once the oracle is stale.
+    pub caller: Signer<'info>,
+
+    #[account(mut, seeds = [CONFIG_SEED], bump)]
+    pub config: Account<'info, Config>,
+
+    #[account(mut)]
+    pub chat: Account<'info, Chat>,
+}
+
+#[derive(Accounts)]
+pub struct ManageContextKeys<'info> {
+    pub owner: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = context_account.owner == owner.key() @ CustomError::ContextOwnerMismatch
+    )]
+    pub context_account: Account<'info, ContextAccount>,
+}
+
+#[derive(Accounts)]
+pub struct ManageContextLimits<'info> {
+    pub owner: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = context_account.owner == owner.key() @ CustomError::ContextOwnerMismatch
+    )]
+    pub context_account: Account<'info, ContextAccount>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateContext<'info> {
+    /// Context owner; pays for growth and receives any freed rent.
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    /// CHECK: may still hold an older layout; re-read and owner-checked in the handler
+    #[account(
+        mut,
+        owner = crate::ID,
+        seeds = [ContextAccount::seed(), owner.key().as_ref()],
+        bump
+    )]
+    pub context_account: UncheckedAccount<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateChat<'info> {
+    /// Anyone may pay to upgrade a chat; chat layouts only ever grow.
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    /// CHECK: may still hold an older layout; re-read in the handler
+    #[account(mut, owner = crate::ID)]
+    pub chat: UncheckedAccount<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[delegate]
+#[derive(Accounts)]
+#[instruction(chat_id: u64)]
+pub struct DelegateChat<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    /// CHECK: the correct chat account
+    #[account(
+        mut, del,
+        seeds = [CHAT_SEED, context_account.key().as_ref(), &chat_id.to_le_bytes()],
+        bump
+    )]
+    /// CHECK: we only use seeds + delegation, no need to deserialize here
+    pub chat: AccountInfo<'info>,
+
+    /// CHECK: we accept any context
+    pub context_account: AccountInfo<'info>,
+
+    #[account(
+        seeds = [CONFIG_SEED],
+        bump,
+        constraint = !config.is_paused(PAUSE_DELEGATE) @ CustomError::Paused
+    )]
+    pub config: Account<'info, Config>,
+}
+
+#[commit]
+#[derive(Accounts)]
+#[instruction(chat_id: u64)]
+pub struct UndelegateChat<'info> {
+    /// CHECK: Matched against the chat account
+    pub user: AccountInfo<'info>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [CHAT_SEED, context_account.key().as_ref(), &chat_id.to_le_bytes()],
+        bump
+    )]
+    pub chat: Account<'info, Chat>,
+
+    /// CHECK: we accept any context
+    pub context_account: AccountInfo<'info>,
+}
+
+/// --------------------------------------------------
+/// Accounts
+/// --------------------------------------------------
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, InitSpace)]
 pub struct ContextAccount {
     pub owner: Pubkey,
     pub next_chat_id: u64,
+    pub keys_revoked: bool, // context-wide kill switch for get_dek
+    pub version: u8,        // 0 = pre-versioning layout, see CONTEXT_VERSION
+
+    /// ---- v2: key hierarchy ----
+    pub master_key: Option<Pubkey>, // chat cmk = HKDF(master_key, "chat:"+chat_id)
+
+    /// ---- v3: prepaid credits ----
+    pub credits: u64,          // total balance, including reservations
+    pub reserved_credits: u64, // sum of Chat::reserved_credits still open
+
+    /// ---- v4: usage limits, enforced by create_chat ----
+    pub limits: ContextLimits,
+    pub recent_chat_count: u64,                 // chats created, ever
+    pub recent_chats: [i64; CHAT_RATE_LOG_LEN], // creation times, slot = count % LEN
+    pub spend_day: i64,                         // unix day that spent_today refers to
+    pub spent_today: u64,                       // credits reserved by chats created that day
+    pub pending_chats: u32,                     // chats created but not yet settled
+}
+
+versioned_account!(ContextAccount, [75, 176, 185, 173, 144, 35, 90, 109]);
+
+impl ContextAccount {
+    pub fn seed() -> &'static [u8] { b"context" }
+
+    /// Counts a new chat costing `price` against the owner's limits.
+    fn record_chat(&mut self, price: u64, now: i64) -> Result<()> {
+        let l = self.limits;
+
+        // rolling window: the max-th most recent chat must have aged out of it
+        let max = u64::from(l.max_chats_per_window);
+        if max > 0 && self.recent_chat_count >= max {
+            let slot = (self.recent_chat_count - max) % CHAT_RATE_LOG_LEN as u64;
+            let oldest = self.recent_chats[slot as usize];
+            require!(
+                now.saturating_sub(oldest) >= i64::from(l.window_secs),
+                CustomError::ChatRateLimited
+            );
+        }
+
+        let day = now.div_euclid(SECONDS_PER_DAY);
+        if day != self.spend_day {
+            self.spend_day = day;
+            self.spent_today = 0;
+        }
+        let spent = self.spent_today.checked_add(price).ok_or(CustomError::MathOverflow)?;
+        require!(
+            l.max_spend_per_day == 0 || spent <= l.max_spend_per_day,
+            CustomError::DailySpendLimit
+        );
+
+        require!(
+            l.max_pending_chats == 0 || self.pending_chats < l.max_pending_chats,
+            CustomError::TooManyPendingChats
+        );
+
+        self.recent_chats[(self.recent_chat_count % CHAT_RATE_LOG_LEN as u64) as usize] = now;
+        self.recent_chat_count += 1;
+        self.spent_today = spent;
+        self.pending_chats += 1;
+        Ok(())
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, InitSpace)]
+pub struct Chat {
+    /// ---- fixed-size header (stable offsets) ----
+    pub context: Pubkey,
+    pub user: Pubkey,
+    pub id: u64,
+    pub created_at: i64, // unix timestamp
+    pub status: u8,
+    pub cmk: Pubkey,
+    pub tx_id: Pubkey,
+    pub key_epoch: u32, // bumped by rotate_cmk; `cmk` is always the latest
+    pub keys_revoked: bool,
+
+    /// ---- get_dek audit trail ----
+    pub dek_access_count: u64,
+    pub dek_accesses: [DekAccess; DEK_ACCESS_LOG_LEN], // slot = count % LEN
+
+    pub version: u8, // see CHAT_VERSION; new fields go below
+
+    /// ---- v2: multi-turn conversations ----
+    pub next_message_index: u64,
+
+    /// ---- v3: oracle error reporting ----
+    pub error_code: ChatErrorCode,
+    pub error_detail_hash: Option<[u8; 32]>, // hash of an off-chain error detail blob
+
+    /// ---- v4: progress while STATUS_RUNNING ----
+    pub progress_percent: u8,
+    pub progress_stage: u8,
+
+    /// ---- v5: lifecycle timestamps (unix), maintained by set_status ----
+    pub updated_at: i64,
+    pub started_at: i64,   // entered STATUS_RUNNING, 0 if never
+    pub completed_at: i64, // entered DONE / ERROR / CANCELLED, 0 while open
+    pub status_changed_slot: u64,
+
+    /// ---- v6: results and consumer callbacks ----
+    pub callback: Option<Callback>,
+    pub result_hash: Option<[u8; 32]>, // set by finalize_chat
+
+    /// ---- v7: model selection ----
+    pub model_id: u32, // key of the Model registry entry
+
+    /// ---- v8: payment, settled when the chat ends ----
+    pub reserved_credits: u64,        // credits held at creation
+    pub payment_mint: Option<Pubkey>, // Some = paid through charge_tokens instead of credits
+    pub tokens_due: u64,              // price in payment_mint base units, fixed at creation
+    pub tokens_charged: bool,         // set by charge_tokens; the price is taken at most once
+
+    /// ---- v9: occupies one of the context's pending slots until settled ----
+    pub counts_as_pending: bool,
+
+    /// ---- v10: oracle assignment ----
+    pub oracle: Pubkey,      // default = pre-registry chat, served by ORACLE_IDENTITY
+    pub oracle_seen_at: i64, // unix; assignment or the assignee's last progress, response or status
+
+    /// ---- v11: set once the current assignee is slashed for missing its deadline ----
+    pub oracle_slashed: bool,
+
+    /// ---- v12: optimistic results ----
+    pub challenge_ends_at: i64, // unix timestamp; meaningful while STATUS_SUBMITTED
+    pub disputed: bool,         // a Dispute is open against result_hash
+
+    /// ---- v13: k-of-n consensus ----
+    pub required_confirmations: u8, // 0 = single assigned oracle, see ResultSubmissions
+}
+
+versioned_account!(Chat, [170, 4, 71, 128, 185, 103, 250, 177]);
+
+/// Owner-set guards against runaway chat creation; 0 disables a limit.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default, InitSpace)]
+pub struct ContextLimits {
+    pub max_chats_per_window: u32, // at most CHAT_RATE_LOG_LEN
+    pub window_secs: u32,          // rolling: counts chats created in the last window_secs
+    pub max_spend_per_day: u64, // in credits
+    pub max_pending_chats: u32,
+}
+
+/// Consumer instruction invoked by finalize_chat.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, InitSpace)]
+pub struct Callback {
+    pub program_id: Pubkey,
+    pub discriminator: [u8; 8],
+    #[max_len(CALLBACK_MAX_ACCOUNTS)]
+    pub accounts: Vec<CallbackAccount>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, InitSpace)]
+pub struct CallbackAccount {
+    pub pubkey: Pubkey,
+    pub is_writable: bool,
+}
+
+impl Chat {
+    pub fn assigned_oracle(&self) -> Pubkey {
+        if self.oracle == Pubkey::default() {
+            ORACLE_IDENTITY
+        } else {
+            self.oracle
+        }
+    }
+
+    /// Seconds since the current assignee was assigned or last showed a sign of
+    /// life. Owner and writer status updates don't count.
+    pub fn oracle_idle_secs(&self, now: i64) -> i64 {
+        now.saturating_sub(self.created_at.max(self.oracle_seen_at))
+    }
+
+    pub fn set_status(&mut self, status: u8, clock: &Clock) {
+        let now = clock.unix_timestamp;
+        match status {
+            STATUS_PENDING => {
+                // re-queued: the previous run no longer counts
+                self.started_at = 0;
+                self.completed_at = 0;
+            }
+            STATUS_RUNNING if self.started_at == 0 => self.started_at = now,
+            STATUS_DONE | STATUS_ERROR | STATUS_CANCELLED => self.completed_at = now,
+            _ => {}
+        }
+        self.status = status;
+        self.updated_at = now;
+        self.status_changed_slot = clock.slot;
+    }
+
+    pub fn record_dek_access(&mut self, caller: Pubkey, slot: u64, timestamp: i64) -> Result<()> {
+        if self.version == 0 {
+            return Ok(()); // pre-versioning layout has no room for the log until migrated
+        }
+        let idx = (self.dek_access_count % DEK_ACCESS_LOG_LEN as u64) as usize;
+        self.dek_accesses[idx] = DekAccess {
+            caller,
+            slot,
+            timestamp,
+        };
+        self.dek_access_count = self
+            .dek_access_count
+            .checked_add(1)
+            .ok_or(CustomError::MathOverflow)?;
+        Ok(())
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default, InitSpace)]
+pub struct DekAccess {
+    pub caller: Pubkey,
+    pub slot: u64,
+    pub timestamp: i64, // unix timestamp
 }
 
-impl ContextAccount { pub fn seed() -> &'static [u8] { b"context" } }
+#[account]
+#[derive(InitSpace)]
+pub struct Message {
+    pub chat: Pubkey,
+    pub index: u64,
+    pub role: u8,           // MESSAGE_ROLE_USER | MESSAGE_ROLE_ASSISTANT
+    pub content: Pubkey,    // off-chain pointer, same shape as Chat::tx_id
+    pub content_hash: [u8; 32],
+    pub created_at: i64,    // unix timestamp
+    pub version: u8,
+}
 
 #[account]
 #[derive(InitSpace)]
-pub struct Chat {
-    /// ---- fixed-size header (stable offsets) ----
-    pub context: Pubkey,
-    pub user: Pubkey,
-    pub id: u64,
-    pub created_at: i64, // unix timestamp
-    pub status: u8,
+pub struct ChatKey {
+    pub chat: Pubkey,
+    pub epoch: u32,
     pub cmk: Pubkey,
-    pub tx_id: Pubkey,
+    pub retired_at: i64, // unix timestamp
+    pub version: u8,
+}
+
+#[account]
+pub struct ChatAccess {
+    pub chat: Pubkey,
+    pub members: Vec<ChatMember>,
+    pub version: u8, // appended like every other layout field
+}
+
+impl ChatAccess {
+    /// 8 discr + 32 chat + 4 vec len + entries + 1 version
+    pub fn space(members: usize) -> usize {
+        8 + 32 + 4 + members * ChatMember::INIT_SPACE + 1
+    }
+
+    pub fn role_of(&self, who: &Pubkey) -> u8 {
+        self.members
+            .iter()
+            .find(|m| m.member == *who)
+            .map_or(ROLE_NONE, |m| m.role)
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, InitSpace)]
+pub struct ChatMember {
+    pub member: Pubkey,
+    pub role: u8,
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct AccessGrant {
+    pub chat: Pubkey,
+    pub grantee: Pubkey,
+    pub expires_at: i64, // unix timestamp
+    pub max_uses: u32,
+    pub remaining_uses: u32,
+    pub version: u8,
 }
 
+#[event_cpi]
 #[derive(Accounts)]
 pub struct UpdateChatStatus<'info> {
-    /// chat.user OR the oracle identity.
+    /// chat.user, the oracle identity, or a writer on the access list.
+    #[account(mut)]
+    pub caller: Signer<'info>,
+
+    #[account(mut)]
+    pub chat: Account<'info, Chat>,
+
+    /// Holds the credit reservation settled by this transition.
+    #[account(
+        mut,
+        constraint = context_account.key() == chat.context @ CustomError::ContextMismatch
+    )]
+    pub context_account: Account<'info, ContextAccount>,
+
+    /// Access list, required only when the caller is a shared writer.
+    pub chat_access: Option<Account<'info, ChatAccess>>,
+
+    #[account(seeds = [CONFIG_SEED], bump)]
+    pub config: Account<'info, Config>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct FinalizeChat<'info> {
+    /// Only the chat's assigned oracle.
+    pub oracle: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = chat.assigned_oracle() == oracle.key() @ CustomError::Unauthorized
+    )]
+    pub chat: Account<'info, Chat>,
+
+    /// Holds the credit reservation settled by this transition.
+    #[account(
+        mut,
+        constraint = context_account.key() == chat.context @ CustomError::ContextMismatch
+    )]
+    pub context_account: Account<'info, ContextAccount>,
+
+    /// CHECK: PDA signer handed to the callback program; holds no data
+    #[account(seeds = [CALLBACK_AUTHORITY_SEED], bump)]
+    pub callback_authority: UncheckedAccount<'info>,
+
+    #[account(seeds = [CONFIG_SEED], bump)]
+    pub config: Account<'info, Config>,
+}
+
+#[derive(Accounts)]
+pub struct SetConsensus<'info> {
+    /// Only the chat creator.
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = chat.user == user.key() @ CustomError::Unauthorized
+    )]
+    pub chat: Account<'info, Chat>,
+
+    #[account(seeds = [CONFIG_SEED], bump)]
+    pub config: Account<'info, Config>,
+
+    #[account(
+        init,
+        payer = user,
+        space = 8 + ResultSubmissions::INIT_SPACE,
+        seeds = [SUBMISSIONS_SEED, chat.key().as_ref()],
+        bump
+    )]
+    pub submissions: Account<'info, ResultSubmissions>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct SubmitResult<'info> {
+    /// Any registered oracle, once per chat.
+    pub oracle: Signer<'info>,
+
+    #[account(seeds = [CONFIG_SEED], bump)]
+    pub config: Account<'info, Config>,
+
+    #[account(mut)]
+    pub chat: Account<'info, Chat>,
+
+    /// Holds the credit reservation settled when consensus completes the chat.
+    #[account(
+        mut,
+        constraint = context_account.key() == chat.context @ CustomError::ContextMismatch
+    )]
+    pub context_account: Account<'info, ContextAccount>,
+
+    #[account(
+        mut,
+        seeds = [SUBMISSIONS_SEED, chat.key().as_ref()],
+        bump
+    )]
+    pub submissions: Account<'info, ResultSubmissions>,
+
+    /// CHECK: PDA signer handed to the callback program; holds no data
+    #[account(seeds = [CALLBACK_AUTHORITY_SEED], bump)]
+    pub callback_authority: UncheckedAccount<'info>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct SettleResult<'info> {
+    /// Anyone; typically the oracle or the consumer.
+    pub caller: Signer<'info>,
+
     #[account(mut)]
+    pub chat: Account<'info, Chat>,
+
+    #[account(
+        mut,
+        constraint = context_account.key() == chat.context @ CustomError::ContextMismatch
+    )]
+    pub context_account: Account<'info, ContextAccount>,
+
+    /// CHECK: PDA signer handed to the callback program; holds no data
+    #[account(seeds = [CALLBACK_AUTHORITY_SEED], bump)]
+    pub callback_authority: UncheckedAccount<'info>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct AbandonResult<'info> {
+    /// The chat's assigned oracle or its owner.
     pub caller: Signer<'info>,
 
+    #[account(seeds = [CONFIG_SEED], bump)]
+    pub config: Account<'info, Config>,
+
+    #[account(mut)]
+    pub chat: Account<'info, Chat>,
+
+    #[account(
+        mut,
+        constraint = context_account.key() == chat.context @ CustomError::ContextMismatch
+    )]
+    pub context_account: Account<'info, ContextAccount>,
+}
+
+#[derive(Accounts)]
+pub struct OpenDispute<'info> {
+    /// The chat owner or any watcher.
+    #[account(mut)]
+    pub challenger: Signer<'info>,
+
+    #[account(seeds = [CONFIG_SEED], bump)]
+    pub config: Account<'info, Config>,
+
+    #[account(mut)]
+    pub chat: Account<'info, Chat>,
+
+    #[account(
+        init,
+        payer = challenger,
+        space = 8 + Dispute::INIT_SPACE,
+        seeds = [DISPUTE_SEED, chat.key().as_ref()],
+        bump
+    )]
+    pub dispute: Account<'info, Dispute>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct ResolveDispute<'info> {
+    #[account(constraint = arbiter.key() == config.arbiter @ CustomError::Unauthorized)]
+    pub arbiter: Signer<'info>,
+
+    #[account(mut, seeds = [CONFIG_SEED], bump)]
+    pub config: Account<'info, Config>,
+
+    #[account(mut)]
+    pub chat: Account<'info, Chat>,
+
+    #[account(
+        mut,
+        constraint = context_account.key() == chat.context @ CustomError::ContextMismatch
+    )]
+    pub context_account: Account<'info, ContextAccount>,
+
+    #[account(
+        mut,
+        seeds = [DISPUTE_SEED, chat.key().as_ref()],
+        bump,
+        has_one = challenger @ CustomError::Unauthorized,
+        close = challenger
+    )]
+    pub dispute: Account<'info, Dispute>,
+
+    /// CHECK: receives the bond refund and, if upheld, the slash
     #[account(mut)]
+    pub challenger: UncheckedAccount<'info>,
+
+    /// CHECK: the chat's assigned oracle; receives a forfeited bond unless the
+    /// chat went through consensus
+    #[account(mut, address = chat.assigned_oracle() @ CustomError::Unauthorized)]
+    pub oracle: UncheckedAccount<'info>,
+
+    /// CHECK: bond PDA of the assigned oracle; slashed only if it is program
+    /// owned, i.e. the oracle staked. Unused on consensus chats.
+    #[account(
+        mut,
+        seeds = [ORACLE_BOND_SEED, chat.assigned_oracle().as_ref()],
+        bump
+    )]
+    pub bond: UncheckedAccount<'info>,
+
+    /// Required on consensus chats: names the submitters behind the result.
+    #[account(seeds = [SUBMISSIONS_SEED, chat.key().as_ref()], bump)]
+    pub submissions: Option<Account<'info, ResultSubmissions>>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct ReportError<'info> {
+    /// Only the chat's assigned oracle reports inference failures.
+    pub oracle: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = chat.assigned_oracle() == oracle.key() @ CustomError::Unauthorized
+    )]
+    pub chat: Account<'info, Chat>,
+
+    /// Holds the credit reservation settled by this transition.
+    #[account(
+        mut,
+        constraint = context_account.key() == chat.context @ CustomError::ContextMismatch
+    )]
+    pub context_account: Account<'info, ContextAccount>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct ReportProgress<'info> {
+    /// Only the chat's assigned oracle.
+    pub oracle: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = chat.assigned_oracle() == oracle.key() @ CustomError::Unauthorized
+    )]
     pub chat: Account<'info, Chat>,
 }
 
 #[account]
 pub struct Identity {}
+
+#[account]
+#[derive(InitSpace)]
+pub struct Config {
+    pub admin: Pubkey,
+    pub version: u8,
+
+    /// ---- v2: credit sales ----
+    pub lamports_per_credit: u64, // 0 = credits not for sale
+
+    /// ---- v3: token payments ----
+    #[max_len(ALLOWED_MINTS_MAX)]
+    pub allowed_mints: Vec<AllowedMint>, // SPL Token or Token-2022 mints accepted for payment
+
+    /// ---- v4: emergency stop ----
+    pub paused: u8, // PAUSE_* bits
+
+    /// ---- v5: oracle registry ----
+    #[max_len(ORACLES_MAX)]
+    pub oracles: Vec<Pubkey>, // empty = ORACLE_IDENTITY serves every chat
+    pub next_oracle: u32,     // round-robin cursor into `oracles`
+
+    /// ---- v6: oracle bonds ----
+    pub min_oracle_bond: u64,       // lamports; 0 = staked registration disabled
+    pub unstake_cooldown_secs: i64,
+    pub deadline_slash: u64,        // lamports taken for a missed deadline
+    pub deadline_secs: i64,         // silence before reassign / slash; 0 = ORACLE_TIMEOUT_SECS
+
+    /// ---- v7: result disputes ----
+    pub arbiter: Pubkey,            // resolves disputes
+    pub challenge_window_secs: i64, // 0 = results are final on submission
+    pub dispute_bond: u64,          // lamports a challenger locks up
+    pub dispute_slash: u64,         // lamports taken from the oracle when upheld
+}
+
+impl Config {
+    pub fn is_paused(&self, flag: u8) -> bool {
+        self.paused & flag != 0
+    }
+
+    fn deregister_oracle(&mut self, key: &Pubkey) {
+        if let Some(idx) = self.oracles.iter().position(|o| o == key) {
+            self.oracles.remove(idx);
+            self.next_oracle = 0;
+        }
+    }
+
+    pub fn allowed_mint(&self, mint: &Pubkey) -> Option<&AllowedMint> {
+        self.allowed_mints.iter().find(|m| m.mint == *mint)
+    }
+
+    pub fn oracle_timeout_secs(&self) -> i64 {
+        if self.deadline_secs > 0 {
+            self.deadline_secs
+        } else {
+            ORACLE_TIMEOUT_SECS
+        }
+    }
+
+    pub fn is_oracle(&self, key: &Pubkey) -> bool {
+        if self.oracles.is_empty() {
+            *key == ORACLE_IDENTITY
+        } else {
+            self.oracles.contains(key)
+        }
+    }
+
+    /// `choice` if it is registered, otherwise the next oracle in round-robin order.
+    fn pick_oracle(&mut self, choice: Option<Pubkey>) -> Result<Pubkey> {
+        if let Some(o) = choice {
+            require!(self.is_oracle(&o), CustomError::OracleNotRegistered);
+            return Ok(o);
+        }
+        if self.oracles.is_empty() {
+            return Ok(ORACLE_IDENTITY);
+        }
+        let i = self.next_oracle as usize % self.oracles.len();
+        self.next_oracle = ((i + 1) % self.oracles.len()) as u32;
+        Ok(self.oracles[i])
+    }
+}
+
+/// A payment mint and what one credit costs in it.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, InitSpace)]
+pub struct AllowedMint {
+    pub mint: Pubkey,
+    pub units_per_credit: u64, // base units, so the mint's decimals are priced in
+}
+
+/// Every oracle answer on a consensus chat, kept after completion so the
+/// dissenting ones can be disputed or slashed.
+#[account]
+#[derive(InitSpace)]
+pub struct ResultSubmissions {
+    pub chat: Pubkey,
+    #[max_len(ORACLES_MAX)]
+    pub submissions: Vec<Submission>,
+    pub agreed_hash: Option<[u8; 32]>, // set once k submissions match
+    pub version: u8,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, InitSpace)]
+pub struct Submission {
+    pub oracle: Pubkey,
+    pub result_hash: [u8; 32],
+    pub submitted_at: i64, // unix timestamp
+}
+
+/// Challenge against a submitted result; holds the challenger's bond.
+#[account]
+#[derive(InitSpace)]
+pub struct Dispute {
+    pub chat: Pubkey,
+    pub challenger: Pubkey,
+    pub bond: u64,
+    pub evidence_hash: [u8; 32], // off-chain justification
+    pub opened_at: i64,          // unix timestamp
+    pub version: u8,
+}
+
+/// Lamports an operator has put up to serve as an oracle. The account's
+/// balance beyond rent is `staked + unstake_amount`.
+#[account]
+#[derive(InitSpace)]
+pub struct OracleBond {
+    pub operator: Pubkey,
+    pub staked: u64,
+    pub unstake_amount: u64,       // cooling down, still slashable
+    pub unstake_requested_at: i64, // unix timestamp, 0 = none pending
+    pub slashed_total: u64,
+    pub version: u8,
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct Model {
+    pub model_id: u32,
+    #[max_len(MODEL_NAME_MAX_LEN)]
+    pub display_name: String,
+    pub price_per_request: u64,
+    pub max_context_size: u32, // tokens
+    pub enabled: bool,
+    pub version: u8,
+}
@@ -0,0 +1,170 @@
+import * as anchor from "@coral-xyz/anchor";
+import { BN, Program, web3 } from "@coral-xyz/anchor";
+import { expect } from "chai";
+import { LoyalConsumer } from "../target/types/loyal_consumer";
+import { LoyalOracle } from "../target/types/loyal_oracle";
+
+describe("loyal-consumer", () => {
+  const provider = anchor.AnchorProvider.env();
+  anchor.setProvider(provider);
+
+  const consumer = anchor.workspace.LoyalConsumer as Program<LoyalConsumer>;
+  const oracle = anchor.workspace.LoyalOracle as Program<LoyalOracle>;
+  // the local wallet doubles as the oracle identity, as in loyal-oracle.ts
+  const oracleKeypair: web3.Keypair = (provider.wallet as any).payer;
+
+  const chatId = new BN(0);
+  const resultHash = Array(32).fill(9);
+
+  const MODEL_ID = 0;
+  const [modelAddress] = web3.PublicKey.findProgramAddressSync(
+    [Buffer.from("model"), new BN(MODEL_ID).toArrayLike(Buffer, "le", 4)],
+    oracle.programId
+  );
+
+  const [requester] = web3.PublicKey.findProgramAddressSync(
+    [Buffer.from("requester")],
+    consumer.programId
+  );
+  const [contextAccount] = web3.PublicKey.findProgramAddressSync(
+    [Buffer.from("context"), requester.toBuffer()],
+    oracle.programId
+  );
+  const [chatAddress] = web3.PublicKey.findProgramAddressSync(
+    [
+      Buffer.from("chat"),
+      contextAccount.toBuffer(),
+      chatId.toArrayLike(Buffer, "le", 8),
+    ],
+    oracle.programId
+  );
+
+  it("Init Config And Model!", async () => {
+    // the config is a singleton, so another test file may have created it
+    const [configAddress] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("config")],
+      oracle.programId
+    );
+    if (!(await oracle.account.config.fetchNullable(configAddress))) {
+      await oracle.methods
+        .initConfig()
+        .accounts({ admin: oracleKeypair.publicKey })
+        .rpc({ skipPreflight: true });
+    }
+    if (!(await oracle.account.model.fetchNullable(modelAddress))) {
+      await oracle.methods
+        .registerModel(MODEL_ID, "test-model", new BN(0), 8192)
+        .accounts({ admin: oracleKeypair.publicKey })
+        .rpc({ skipPreflight: true });
+    }
+  });
+
+  it("Initialize Requester!", async () => {
+    await consumer.methods
+      .initialize()
+      .accounts({
+        payer: provider.wallet.publicKey,
+      })
+      .rpc({ skipPreflight: true });
+  });
+
+  it("Open Context Owned By A PDA!", async () => {
+    await consumer.methods
+      .openContext()
+      .accounts({
+        payer: provider.wallet.publicKey,
+      })
+      .rpc({ skipPreflight: true });
+
+    const context = await oracle.account.contextAccount.fetch(contextAccount);
+    expect(context.owner.toBase58()).to.equal(requester.toBase58());
+  });
+
+  it("Request Inference Through CPI!", async () => {
+    await consumer.methods
+      .requestInference(
+        chatId,
+        web3.Keypair.generate().publicKey,
+        web3.Keypair.generate().publicKey,
+        MODEL_ID
+      )
+      .accounts({
+        payer: provider.wallet.publicKey,
+        model: modelAddress,
+      })
+      .rpc({ skipPreflight: true });
+
+    const chat = await oracle.account.chat.fetch(chatAddress);
+    expect(chat.user.toBase58()).to.equal(requester.toBase58());
+    expect(chat.callback.programId.toBase58()).to.equal(
+      consumer.programId.toBase58()
+    );
+  });
+
+  it("Finalize Invokes The Callback!", async () => {
+    await oracle.methods
+      .finalizeChat(resultHash)
+      .accounts({
+        oracle: oracleKeypair.publicKey,
+        chat: chatAddress,
+        contextAccount: contextAccount,
+      })
+      .remainingAccounts([
+        { pubkey: consumer.programId, isSigner: false, isWritable: false },
+        { pubkey: requester, isSigner: false, isWritable: true },
+      ])
+      .signers([oracleKeypair])
+      .rpc({ skipPreflight: true });
+
+    const state = await consumer.account.requester.fetch(requester);
+    expect(state.lastChat.toBase58()).to.equal(chatAddress.toBase58());
+    expect(state.lastResultHash).to.deep.equal(resultHash);
+  });
+
+  it("Results Arrive For Any Chat In Flight!", async () => {
+    const chats = [];
+    for (const id of [new BN(1), new BN(2)]) {
+      await consumer.methods
+        .requestInference(
+          id,
+          web3.Keypair.generate().publicKey,
+          web3.Keypair.generate().publicKey,
+          MODEL_ID
+        )
+        .accounts({
+          payer: provider.wallet.publicKey,
+          model: modelAddress,
+        })
+        .rpc({ skipPreflight: true });
+      const [address] = web3.PublicKey.findProgramAddressSync(
+        [
+          Buffer.from("chat"),
+          contextAccount.toBuffer(),
+          id.toArrayLike(Buffer, "le", 8),
+        ],
+        oracle.programId
+      );
+      chats.push(address);
+    }
+
+    // the older request finishes after a newer one was made
+    const olderHash = Array(32).fill(3);
+    await oracle.methods
+      .finalizeChat(olderHash)
+      .accounts({
+        oracle: oracleKeypair.publicKey,
+        chat: chats[0],
+        contextAccount: contextAccount,
+      })
+      .remainingAccounts([
+        { pubkey: consumer.programId, isSigner: false, isWritable: false },
+        { pubkey: requester, isSigner: false, isWritable: true },
+      ])
+      .signers([oracleKeypair])
+      .rpc({ skipPreflight: true });
+
+    const state = await consumer.account.requester.fetch(requester);
+    expect(state.lastChat.toBase58()).to.equal(chats[0].toBase58());
+    expect(state.lastResultHash).to.deep.equal(olderHash);
+  });
+});
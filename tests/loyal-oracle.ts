@@ -1,9 +1,18 @@
 import * as anchor from "@coral-xyz/anchor";
 import { BN, Program, web3 } from "@coral-xyz/anchor";
+import {
+  TOKEN_2022_PROGRAM_ID,
+  TOKEN_PROGRAM_ID,
+  createMint,
+  getAccount,
+  getOrCreateAssociatedTokenAccount,
+  mintTo,
+} from "@solana/spl-token";
 import { expect } from "chai";
+import { hkdfSync } from "crypto";
 import { LoyalOracle } from "../target/types/loyal_oracle";
 
-describe.only("loyal-oracle", () => {
+describe("loyal-oracle", () => {
   const baseProvider = anchor.AnchorProvider.env();
   // anchor.setProvider(provider);
   const cmk = web3.Keypair.generate().publicKey;
@@ -12,6 +21,7 @@ describe.only("loyal-oracle", () => {
   const chatId = new BN(0);
   const STATUS_PENDING = 1;
   const STATUS_DONE = 2;
+  const CHAT_VERSION = 13;
   const oracleKeypair: web3.Keypair = (baseProvider.wallet as any).payer;
 
   const testKeypair = web3.Keypair.generate();
@@ -59,6 +69,68 @@ describe.only("loyal-oracle", () => {
     program.programId
   );
 
+  // finalized by the oracle in "Finalize Chat!"
+  const secondChatId = new BN(1);
+  const [secondChat] = web3.PublicKey.findProgramAddressSync(
+    [
+      Buffer.from("chat"),
+      contextAccount.toBuffer(),
+      secondChatId.toArrayLike(Buffer, "le", 8),
+    ],
+    program.programId
+  );
+
+  const MODEL_ID = 0;
+  const [modelAddress] = web3.PublicKey.findProgramAddressSync(
+    [Buffer.from("model"), new BN(MODEL_ID).toArrayLike(Buffer, "le", 4)],
+    program.programId
+  );
+
+  // Events are emitted with emit_cpi!, so they live in inner instruction data
+  // rather than in the logs.
+  const cpiEvents = async (signature: string) => {
+    const tx = await provider.connection.getTransaction(signature, {
+      commitment: "confirmed",
+      maxSupportedTransactionVersion: 0,
+    });
+    return (tx?.meta?.innerInstructions ?? [])
+      .flatMap((inner) => inner.instructions)
+      .map((ix) => Buffer.from(anchor.utils.bytes.bs58.decode(ix.data)))
+      .map((data) =>
+        program.coder.events.decode(
+          anchor.utils.bytes.base64.encode(data.subarray(8))
+        )
+      )
+      .filter((event) => event !== null);
+  };
+
+  const expectError = async (call: () => Promise<unknown>, error: string) => {
+    let failed = false;
+    try {
+      await call();
+    } catch (e) {
+      failed = true;
+      expect(String(e)).to.contain(error);
+    }
+    expect(failed).to.equal(true);
+  };
+
+  // the test context's next chat, for tests that just need a fresh one
+  const nextChat = async () => {
+    const { nextChatId } = await program.account.contextAccount.fetch(
+      contextAccount
+    );
+    const [address] = web3.PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("chat"),
+        contextAccount.toBuffer(),
+        nextChatId.toArrayLike(Buffer, "le", 8),
+      ],
+      program.programId
+    );
+    return { id: nextChatId, address };
+  };
+
   before(async () => {
     const { blockhash, lastValidBlockHeight } =
       await provider.connection.getLatestBlockhash();
@@ -99,11 +171,37 @@ describe.only("loyal-oracle", () => {
     console.log("Your transaction signature", tx);
   });
 
+  it("Init Config And Model!", async () => {
+    // the config is a singleton, so another test file may have created it
+    const [configAddress] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("config")],
+      program.programId
+    );
+    if (!(await program.account.config.fetchNullable(configAddress))) {
+      await program.methods
+        .initConfig()
+        .accounts({ admin: oracleKeypair.publicKey })
+        .signers([oracleKeypair])
+        .rpc({ skipPreflight: true });
+    }
+    if (!(await program.account.model.fetchNullable(modelAddress))) {
+      await program.methods
+        .registerModel(MODEL_ID, "test-model", new BN(0), 8192)
+        .accounts({ admin: oracleKeypair.publicKey })
+        .signers([oracleKeypair])
+        .rpc({ skipPreflight: true });
+    }
+
+    const model = await program.account.model.fetch(modelAddress);
+    expect(model.enabled).to.equal(true);
+  });
+
   it("Create Context!", async () => {
     const tx = await program.methods
       .createContext()
       .accounts({
         payer: provider.wallet.publicKey,
+        owner: provider.wallet.publicKey,
       })
       .rpc({ skipPreflight: true });
     console.log("Your transaction signature", tx);
@@ -112,14 +210,176 @@ describe.only("loyal-oracle", () => {
     console.log("context", context);
   });
 
+  // Context and chat 0 in the deployed pre-versioning layouts, preloaded from
+  // tests/fixtures/legacy-{context,chat}.json and owned by this seeded keypair
+  const legacyOwner = web3.Keypair.fromSeed(new Uint8Array(32).fill(76));
+  const legacyContext = new web3.PublicKey(
+    "D2MXDXs3sZYT18BvJgPDbVyEYqpKb7ukjX8Pu4kRmFyi"
+  );
+  const legacyChat = new web3.PublicKey(
+    "FPceFrWufMbuVxJX31332ANH61vRQnw84tjwwYCwDdBb"
+  );
+  const legacyGetDek = () =>
+    program.methods
+      .getDek(0)
+      .accounts({
+        caller: legacyOwner.publicKey,
+        chat: legacyChat,
+        contextAccount: legacyContext,
+        chatKey: null,
+        chatAccess: null,
+        accessGrant: null,
+      })
+      .signers([legacyOwner])
+      .rpc({ commitment: "confirmed" });
+
+  it("Legacy Accounts Load Before Migration!", async () => {
+    expect(
+      (await provider.connection.getAccountInfo(legacyContext)).data.length
+    ).to.equal(52);
+    expect(
+      (await provider.connection.getAccountInfo(legacyChat)).data.length
+    ).to.equal(153);
+
+    // dek = HKDF-SHA256(cmk, no salt, info = "file:" || tx_id)
+    const data = (await provider.connection.getAccountInfo(legacyChat)).data;
+    const cmk = data.subarray(89, 121);
+    const legacyTxId = data.subarray(121, 153);
+    const expected = Buffer.from(
+      hkdfSync(
+        "sha256",
+        cmk,
+        Buffer.alloc(0),
+        Buffer.concat([Buffer.from("file:"), legacyTxId]),
+        32
+      )
+    );
+
+    const [evt] = await cpiEvents(await legacyGetDek());
+    expect(Buffer.from(evt.data.dek).equals(expected)).to.equal(true);
+    expect(evt.data.chatId.toNumber()).to.equal(0);
+
+    // nothing outside the old layout was written, so it kept its size
+    expect(
+      (await provider.connection.getAccountInfo(legacyChat)).data.length
+    ).to.equal(153);
+
+    // cancelling stamps completed_at, which only the migrated layout has room for
+    await expectError(
+      () =>
+        program.methods
+          .cancelChat()
+          .accounts({
+            user: legacyOwner.publicKey,
+            chat: legacyChat,
+            contextAccount: legacyContext,
+          })
+          .signers([legacyOwner])
+          .rpc({ skipPreflight: true }),
+      "MigrationRequired"
+    );
+  });
+
+  it("Migrate Context!", async () => {
+    const { blockhash, lastValidBlockHeight } =
+      await provider.connection.getLatestBlockhash();
+    await provider.connection.confirmTransaction(
+      {
+        signature: await provider.connection.requestAirdrop(
+          legacyOwner.publicKey,
+          web3.LAMPORTS_PER_SOL
+        ),
+        blockhash,
+        lastValidBlockHeight,
+      },
+      "confirmed"
+    );
+
+    await program.methods
+      .migrateContext()
+      .accounts({
+        owner: legacyOwner.publicKey,
+      })
+      .signers([legacyOwner])
+      .rpc({ skipPreflight: true });
+
+    const after = await provider.connection.getAccountInfo(legacyContext);
+    expect(after.data.length).to.be.greaterThan(52);
+    const context = await program.account.contextAccount.fetch(legacyContext);
+    expect(context.version).to.equal(4);
+    expect(context.owner.toBase58()).to.equal(
+      legacyOwner.publicKey.toBase58()
+    );
+    expect(context.nextChatId.toNumber()).to.equal(1);
+    expect(context.pendingChats).to.equal(0);
+  });
+
+  it("Migrate Legacy Chat!", async () => {
+    await program.methods
+      .migrateChat()
+      .accounts({
+        payer: provider.wallet.publicKey,
+        chat: legacyChat,
+      })
+      .rpc({ skipPreflight: true });
+
+    const after = await provider.connection.getAccountInfo(legacyChat);
+    expect(after.data.length).to.be.greaterThan(153);
+    let chat = await program.account.chat.fetch(legacyChat);
+    expect(chat.version).to.equal(CHAT_VERSION);
+    expect(chat.id.toNumber()).to.equal(0);
+    expect(chat.context.toBase58()).to.equal(legacyContext.toBase58());
+    expect(chat.user.toBase58()).to.equal(legacyOwner.publicKey.toBase58());
+    expect(chat.status).to.equal(STATUS_PENDING);
+    expect(chat.dekAccessCount.toNumber()).to.equal(0);
+
+    // with room for it, key access is logged again
+    await legacyGetDek();
+    chat = await program.account.chat.fetch(legacyChat);
+    expect(chat.dekAccessCount.toNumber()).to.equal(1);
+  });
+
+  it("Buy Credits!", async () => {
+    await program.methods
+      .setCreditPrice(new BN(1000))
+      .accounts({ admin: oracleKeypair.publicKey })
+      .signers([oracleKeypair])
+      .rpc({ skipPreflight: true });
+
+    const before = await program.account.contextAccount.fetch(contextAccount);
+    await program.methods
+      .buyCredits(new BN(10))
+      .accounts({
+        owner: provider.wallet.publicKey,
+        contextAccount: contextAccount,
+      })
+      .rpc({ skipPreflight: true });
+
+    const context = await program.account.contextAccount.fetch(contextAccount);
+    expect(context.credits.sub(before.credits).toNumber()).to.equal(10);
+
+    // calling create_context again must not wipe the balance
+    await program.methods
+      .createContext()
+      .accounts({
+        payer: provider.wallet.publicKey,
+        owner: provider.wallet.publicKey,
+      })
+      .rpc({ skipPreflight: true });
+    const again = await program.account.contextAccount.fetch(contextAccount);
+    expect(again.credits.toString()).to.equal(context.credits.toString());
+  });
+
   it("Create Chat!", async () => {
     const chatId = new BN(0);
 
     const tx = await program.methods
-      .createChat(chatId, cmk, txId)
+      .createChat(chatId, cmk, txId, null, MODEL_ID, null, null)
       .accounts({
         payer: provider.wallet.publicKey,
+        owner: provider.wallet.publicKey,
         contextAccount: contextAccount,
+        model: modelAddress,
       })
       .rpc({ skipPreflight: true });
     console.log("Your transaction signature", tx);
@@ -128,90 +388,1347 @@ describe.only("loyal-oracle", () => {
     console.log("chat", chat);
   });
 
-  it("Get DEK for user!", async () => {
-    const eventP = new Promise<{
-      name: string;
-      data: { chat: anchor.web3.PublicKey; chatId: anchor.BN; dek: Buffer[] };
-      slot: number;
-      signature?: string;
-    }>(async (resolve) => {
-      const listener = await program.addEventListener(
-        "dekResponse",
-        (event, slot, signature) => {
-          program.removeEventListener(listener).catch(() => {});
-          resolve({ name: "dekResponse", data: event as any, slot, signature });
-        }
-      );
+  it("Pending Chat Limit Is Enforced!", async () => {
+    const limits = (maxPendingChats: number) => ({
+      maxChatsPerWindow: 0,
+      windowSecs: 0,
+      maxSpendPerDay: new BN(0),
+      maxPendingChats,
     });
 
+    // chat 0 is still pending, so a limit of one blocks the next chat
+    await program.methods
+      .setContextLimits(limits(1))
+      .accounts({
+        owner: provider.wallet.publicKey,
+        contextAccount: contextAccount,
+      })
+      .rpc({ skipPreflight: true });
+
+    let failed = false;
+    try {
+      await program.methods
+        .createChat(new BN(1), cmk, txId, null, MODEL_ID, null, null)
+        .accounts({
+          payer: provider.wallet.publicKey,
+          owner: provider.wallet.publicKey,
+          contextAccount: contextAccount,
+          model: modelAddress,
+        })
+        .rpc();
+    } catch (e) {
+      failed = true;
+      expect(String(e)).to.contain("TooManyPendingChats");
+    }
+    expect(failed).to.equal(true);
+
     await program.methods
-      .getDek()
+      .setContextLimits(limits(0))
+      .accounts({
+        owner: provider.wallet.publicKey,
+        contextAccount: contextAccount,
+      })
+      .rpc({ skipPreflight: true });
+  });
+
+  it("Rolling Chat Window Is Enforced!", async () => {
+    const setLimits = (maxChatsPerWindow: number) =>
+      program.methods
+        .setContextLimits({
+          maxChatsPerWindow,
+          windowSecs: 3600,
+          maxSpendPerDay: new BN(0),
+          maxPendingChats: 0,
+        })
+        .accounts({
+          owner: provider.wallet.publicKey,
+          contextAccount: contextAccount,
+        })
+        .rpc();
+
+    // the ring only remembers 32 creations
+    await expectError(() => setLimits(33), "InvalidLimits");
+
+    // chat 0 was created within the last hour
+    await setLimits(1);
+    await expectError(
+      () =>
+        program.methods
+          .createChat(new BN(1), cmk, txId, null, MODEL_ID, null, null)
+          .accounts({
+            payer: provider.wallet.publicKey,
+            owner: provider.wallet.publicKey,
+            contextAccount: contextAccount,
+            model: modelAddress,
+          })
+          .rpc(),
+      "ChatRateLimited"
+    );
+
+    await setLimits(0);
+  });
+
+  it("Escrow Tokens!", async () => {
+    const mint = await createMint(
+      provider.connection,
+      oracleKeypair,
+      oracleKeypair.publicKey,
+      null,
+      6
+    );
+    const source = await getOrCreateAssociatedTokenAccount(
+      provider.connection,
+      oracleKeypair,
+      mint,
+      provider.wallet.publicKey
+    );
+    await mintTo(
+      provider.connection,
+      oracleKeypair,
+      mint,
+      source.address,
+      oracleKeypair,
+      1_000
+    );
+
+    await program.methods
+      .addAllowedMint(mint, new BN(1))
+      .accounts({ admin: oracleKeypair.publicKey })
+      .signers([oracleKeypair])
+      .rpc({ skipPreflight: true });
+
+    await program.methods
+      .depositTokens(new BN(500))
+      .accounts({
+        owner: provider.wallet.publicKey,
+        mint,
+        source: source.address,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .rpc({ skipPreflight: true });
+
+    await program.methods
+      .withdrawTokens(new BN(500))
+      .accounts({
+        owner: provider.wallet.publicKey,
+        mint,
+        destination: source.address,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .rpc({ skipPreflight: true });
+
+    const [vault] = web3.PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("token_vault"),
+        provider.wallet.publicKey.toBuffer(),
+        mint.toBuffer(),
+      ],
+      program.programId
+    );
+    expect(
+      Number((await getAccount(provider.connection, vault)).amount)
+    ).to.equal(0);
+    expect(
+      Number((await getAccount(provider.connection, source.address)).amount)
+    ).to.equal(1_000);
+  });
+
+  it("Append Messages!", async () => {
+    const messageAddress = (index: number) =>
+      web3.PublicKey.findProgramAddressSync(
+        [
+          Buffer.from("message"),
+          chatAddress.toBuffer(),
+          new BN(index).toArrayLike(Buffer, "le", 8),
+        ],
+        program.programId
+      )[0];
+
+    await program.methods
+      .appendMessage(new BN(0), web3.Keypair.generate().publicKey, Array(32).fill(1))
+      .accounts({
+        user: provider.wallet.publicKey,
+        chat: chatAddress,
+      })
+      .rpc({ skipPreflight: true });
+
+    await program.methods
+      .appendResponse(new BN(1), web3.Keypair.generate().publicKey, Array(32).fill(2))
+      .accounts({
+        oracle: oracleKeypair.publicKey,
+        chat: chatAddress,
+      })
+      .signers([oracleKeypair])
+      .rpc({ skipPreflight: true });
+
+    const chat = await program.account.chat.fetch(chatAddress);
+    expect(chat.nextMessageIndex.toNumber()).to.equal(2);
+
+    const reply = await program.account.message.fetch(messageAddress(1));
+    expect(reply.role).to.equal(2);
+
+    // the user authored message 0, so they may derive its key
+    await program.methods
+      .getMessageDek(0, new BN(0))
       .accounts({
         caller: provider.wallet.publicKey,
         chat: chatAddress,
+        message: messageAddress(0),
+        contextAccount: contextAccount,
+        chatKey: null,
       })
       .rpc();
+  });
 
-    const evt = await eventP;
+  it("Get DEK for user!", async () => {
+    const userTx = await program.methods
+      .getDek(0)
+      .accounts({
+        caller: provider.wallet.publicKey,
+        chat: chatAddress,
+        contextAccount: contextAccount,
+        chatKey: null,
+        chatAccess: null,
+        accessGrant: null,
+      })
+      .rpc({ commitment: "confirmed" });
+
+    const [evt] = await cpiEvents(userTx);
+    expect(evt.name).to.equal("dekResponse");
+    expect(evt.data.schemaVersion).to.equal(1);
     const userDek = evt.data.dek;
     console.log("userDek", userDek);
-    await new Promise((resolve) => setTimeout(resolve, 500));
-
-    const eventPO = new Promise<{
-      name: string;
-      data: { chat: anchor.web3.PublicKey; chatId: anchor.BN; dek: Buffer[] };
-      slot: number;
-      signature?: string;
-    }>(async (resolve) => {
-      const listener = await program.addEventListener(
-        "dekResponse",
-        (event, slot, signature) => {
-          program.removeEventListener(listener).catch(() => {});
-          resolve({ name: "dekResponse", data: event as any, slot, signature });
-        }
-      );
-    });
 
-    await program.methods
-      .getDek()
+    const oracleTx = await program.methods
+      .getDek(0)
       .accounts({
         caller: oracleKeypair.publicKey,
         chat: chatAddress,
+        contextAccount: contextAccount,
+        chatKey: null,
+        chatAccess: null,
+        accessGrant: null,
       })
       .signers([oracleKeypair])
-      .rpc();
+      .rpc({ commitment: "confirmed" });
 
-    console.log("awaiting oracle dek");
-
-    const evtO = await eventPO;
+    const [evtO] = await cpiEvents(oracleTx);
     const oracleDek = evtO.data.dek;
     console.log("oracleDek", oracleDek);
+    expect(Buffer.from(oracleDek).equals(Buffer.from(userDek))).to.equal(true);
+    expect(evtO.data.actor.toBase58()).to.equal(
+      oracleKeypair.publicKey.toBase58()
+    );
+
+    const chat = await program.account.chat.fetch(chatAddress);
+    expect(chat.dekAccessCount.toNumber()).to.equal(2);
+    expect(chat.dekAccesses[1].caller.toBase58()).to.equal(
+      oracleKeypair.publicKey.toBase58()
+    );
+  });
+
+  it("Paused DEK Issuance Is Rejected!", async () => {
+    const PAUSE_GET_DEK = 1 << 1;
+    await program.methods
+      .pause(PAUSE_GET_DEK)
+      .accounts({ admin: oracleKeypair.publicKey })
+      .signers([oracleKeypair])
+      .rpc({ skipPreflight: true });
+
+    let failed = false;
+    try {
+      await program.methods
+        .getDek(0)
+        .accounts({
+          caller: provider.wallet.publicKey,
+          chat: chatAddress,
+          contextAccount: contextAccount,
+          chatKey: null,
+          chatAccess: null,
+          accessGrant: null,
+        })
+        .rpc();
+    } catch (e) {
+      failed = true;
+      expect(String(e)).to.contain("Paused");
+    }
+    expect(failed).to.equal(true);
+
+    await program.methods
+      .unpause(PAUSE_GET_DEK)
+      .accounts({ admin: oracleKeypair.publicKey })
+      .signers([oracleKeypair])
+      .rpc({ skipPreflight: true });
   });
 
-  it("Update Status From Oracle!", async () => {
+  it("Rotate CMK!", async () => {
+    const newCmk = web3.Keypair.generate().publicKey;
+    const [chatKeyAddress] = web3.PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("chat_key"),
+        chatAddress.toBuffer(),
+        new BN(0).toArrayLike(Buffer, "le", 4),
+      ],
+      program.programId
+    );
+
     const tx = await program.methods
-      .updateStatus(STATUS_DONE)
+      .rotateCmk(newCmk)
       .accounts({
-        caller: provider.wallet.publicKey,
+        user: provider.wallet.publicKey,
         chat: chatAddress,
       })
       .rpc({ skipPreflight: true });
     console.log("Your transaction signature", tx);
-    let chat = await program.account.chat.fetch(chatAddress);
+
+    const chat = await program.account.chat.fetch(chatAddress);
+    expect(chat.keyEpoch).to.equal(1);
+    expect(chat.cmk.toBase58()).to.equal(newCmk.toBase58());
+
+    const chatKey = await program.account.chatKey.fetch(chatKeyAddress);
+    expect(chatKey.epoch).to.equal(0);
+    expect(chatKey.cmk.toBase58()).to.equal(cmk.toBase58());
+
+    // the retired epoch is still served through its archive account
+    await program.methods
+      .getDek(0)
+      .accounts({
+        caller: provider.wallet.publicKey,
+        chat: chatAddress,
+        contextAccount: contextAccount,
+        chatKey: chatKeyAddress,
+        chatAccess: null,
+        accessGrant: null,
+      })
+      .rpc();
+  });
+
+  it("Revoke Chat Keys!", async () => {
+    await program.methods
+      .revokeChatKeys()
+      .accounts({
+        user: provider.wallet.publicKey,
+        chat: chatAddress,
+      })
+      .rpc({ skipPreflight: true });
+
+    let failed = false;
+    try {
+      await program.methods
+        .getDek(1)
+        .accounts({
+          caller: oracleKeypair.publicKey,
+          chat: chatAddress,
+          contextAccount: contextAccount,
+          chatKey: null,
+          chatAccess: null,
+          accessGrant: null,
+        })
+        .signers([oracleKeypair])
+        .rpc();
+    } catch (e) {
+      failed = true;
+      expect(String(e)).to.contain("KeysRevoked");
+    }
+    expect(failed).to.equal(true);
+
+    await program.methods
+      .restoreChatKeys()
+      .accounts({
+        user: provider.wallet.publicKey,
+        chat: chatAddress,
+      })
+      .rpc({ skipPreflight: true });
+    const chat = await program.account.chat.fetch(chatAddress);
+    expect(chat.keysRevoked).to.equal(false);
+  });
+
+  it("Share Chat With A Reader!", async () => {
+    const ROLE_READER = 1;
+    const [chatAccessAddress] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("chat_access"), chatAddress.toBuffer()],
+      program.programId
+    );
+
+    await program.methods
+      .createChatAccess()
+      .accounts({
+        user: provider.wallet.publicKey,
+        chat: chatAddress,
+      })
+      .rpc({ skipPreflight: true });
+
+    await program.methods
+      .grantAccess(oracleKeypair.publicKey, ROLE_READER)
+      .accounts({
+        user: provider.wallet.publicKey,
+        chat: chatAddress,
+      })
+      .rpc({ skipPreflight: true });
+
+    let access = await program.account.chatAccess.fetch(chatAccessAddress);
+    expect(access.members.length).to.equal(1);
+    expect(access.members[0].role).to.equal(ROLE_READER);
+
+    // re-granting an existing member only changes the role
+    await program.methods
+      .grantAccess(oracleKeypair.publicKey, 2)
+      .accounts({
+        user: provider.wallet.publicKey,
+        chat: chatAddress,
+      })
+      .rpc({ skipPreflight: true });
+
+    access = await program.account.chatAccess.fetch(chatAccessAddress);
+    expect(access.members.length).to.equal(1);
+    expect(access.members[0].role).to.equal(2);
+
+    await program.methods
+      .revokeAccess(oracleKeypair.publicKey)
+      .accounts({
+        user: provider.wallet.publicKey,
+        chat: chatAddress,
+      })
+      .rpc({ skipPreflight: true });
+
+    access = await program.account.chatAccess.fetch(chatAccessAddress);
+    expect(access.members.length).to.equal(0);
+  });
+
+  it("Grant Temporary Access!", async () => {
+    const grantee = oracleKeypair.publicKey;
+    const [grantAddress] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("access_grant"), chatAddress.toBuffer(), grantee.toBuffer()],
+      program.programId
+    );
+    const expiresAt = new BN(Math.floor(Date.now() / 1000) + 3600);
+
+    await program.methods
+      .createAccessGrant(grantee, expiresAt, 2)
+      .accounts({
+        user: provider.wallet.publicKey,
+        chat: chatAddress,
+      })
+      .rpc({ skipPreflight: true });
+
+    const grant = await program.account.accessGrant.fetch(grantAddress);
+    expect(grant.remainingUses).to.equal(2);
+
+    await program.methods
+      .closeAccessGrant()
+      .accounts({
+        user: provider.wallet.publicKey,
+        chat: chatAddress,
+        accessGrant: grantAddress,
+      })
+      .rpc({ skipPreflight: true });
+
+    const closed = await provider.connection.getAccountInfo(grantAddress);
+    expect(closed).to.equal(null);
+  });
+
+  it("Report Progress From Oracle!", async () => {
+    await program.methods
+      .reportProgress(40, 1)
+      .accounts({
+        oracle: oracleKeypair.publicKey,
+        chat: chatAddress,
+      })
+      .signers([oracleKeypair])
+      .rpc({ skipPreflight: true });
+
+    const chat = await program.account.chat.fetch(chatAddress);
+    expect(chat.status).to.equal(5);
+    expect(chat.progressPercent).to.equal(40);
+    expect(chat.startedAt.toNumber()).to.be.greaterThan(0);
+    expect(chat.oracleSeenAt.toNumber()).to.be.greaterThan(0);
+  });
+
+  it("Report Error From Oracle!", async () => {
+    await program.methods
+      .reportError({ timeout: {} }, null)
+      .accounts({
+        oracle: oracleKeypair.publicKey,
+        chat: chatAddress,
+        contextAccount: contextAccount,
+      })
+      .signers([oracleKeypair])
+      .rpc({ skipPreflight: true });
+
+    const chat = await program.account.chat.fetch(chatAddress);
+    expect(chat.status).to.equal(3);
+    expect(chat.errorCode).to.deep.equal({ timeout: {} });
+
+    // a settled chat cannot be errored (and re-settled) again
+    let failed = false;
+    try {
+      await program.methods
+        .reportError({ timeout: {} }, null)
+        .accounts({
+          oracle: oracleKeypair.publicKey,
+          chat: chatAddress,
+          contextAccount: contextAccount,
+        })
+        .signers([oracleKeypair])
+        .rpc({ skipPreflight: true });
+    } catch (e) {
+      failed = true;
+      expect(String(e)).to.contain("ChatNotActive");
+    }
+    expect(failed).to.equal(true);
+  });
+
+  it("Status Is Final After An Error!", async () => {
+    // the owner cannot mark their own chat done to skip payment
+    let failed = false;
+    try {
+      await program.methods
+        .updateStatus(STATUS_DONE)
+        .accounts({
+          caller: provider.wallet.publicKey,
+          chat: chatAddress,
+          contextAccount: contextAccount,
+          chatAccess: null,
+        })
+        .rpc();
+    } catch (e) {
+      failed = true;
+      expect(String(e)).to.contain("Unauthorized");
+    }
+    expect(failed).to.equal(true);
+
+    // nor can the oracle reopen a chat it already failed
+    failed = false;
+    try {
+      await program.methods
+        .updateStatus(STATUS_DONE)
+        .accounts({
+          caller: oracleKeypair.publicKey,
+          chat: chatAddress,
+          contextAccount: contextAccount,
+          chatAccess: null,
+        })
+        .signers([oracleKeypair])
+        .rpc();
+    } catch (e) {
+      failed = true;
+      expect(String(e)).to.contain("ChatNotActive");
+    }
+    expect(failed).to.equal(true);
+
+    const chat = await program.account.chat.fetch(chatAddress);
+    expect(chat.status).to.equal(3);
+    expect(chat.completedAt.toNumber()).to.be.greaterThan(0);
+  });
+
+  it("Finalize Chat!", async () => {
+    await program.methods
+      .createChat(secondChatId, cmk, txId, null, MODEL_ID, null, null)
+      .accounts({
+        payer: provider.wallet.publicKey,
+        owner: provider.wallet.publicKey,
+        contextAccount: contextAccount,
+        model: modelAddress,
+      })
+      .rpc({ skipPreflight: true });
+
+    // no callback registered, so there are no remaining accounts
+    await program.methods
+      .finalizeChat(Array(32).fill(7))
+      .accounts({
+        oracle: oracleKeypair.publicKey,
+        chat: secondChat,
+        contextAccount: contextAccount,
+      })
+      .signers([oracleKeypair])
+      .rpc({ skipPreflight: true });
+
+    const chat = await program.account.chat.fetch(secondChat);
     expect(chat.status).to.equal(STATUS_DONE);
+    expect(chat.resultHash).to.deep.equal(Array(32).fill(7));
+  });
+
+  it("Cancel Is Rejected After Done Or Error!", async () => {
+    // secondChat is DONE, chatAddress ended in ERROR
+    for (const chat of [secondChat, chatAddress]) {
+      let failed = false;
+      try {
+        await program.methods
+          .cancelChat()
+          .accounts({
+            user: provider.wallet.publicKey,
+            chat,
+            contextAccount: contextAccount,
+          })
+          .rpc();
+      } catch (e) {
+        failed = true;
+        expect(String(e)).to.contain("CannotCancel");
+      }
+      expect(failed).to.equal(true);
+    }
+  });
+
+  it("Reassign Oracle!", async () => {
+    const otherOracle = web3.Keypair.generate().publicKey;
+    const thirdChatId = new BN(2);
+    const [thirdChat] = web3.PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("chat"),
+        contextAccount.toBuffer(),
+        thirdChatId.toArrayLike(Buffer, "le", 8),
+      ],
+      program.programId
+    );
+
+    for (const oracle of [oracleKeypair.publicKey, otherOracle]) {
+      await program.methods
+        .addOracle(oracle)
+        .accounts({ admin: oracleKeypair.publicKey })
+        .signers([oracleKeypair])
+        .rpc({ skipPreflight: true });
+    }
+
+    await program.methods
+      .createChat(thirdChatId, cmk, txId, null, MODEL_ID, otherOracle, null)
+      .accounts({
+        payer: provider.wallet.publicKey,
+        owner: provider.wallet.publicKey,
+        contextAccount: contextAccount,
+        model: modelAddress,
+      })
+      .rpc({ skipPreflight: true });
+    let chat = await program.account.chat.fetch(thirdChat);
+    expect(chat.oracle.toBase58()).to.equal(otherOracle.toBase58());
 
-    const txOracle = await program.methods
-      .updateStatus(STATUS_DONE)
+    // the admin may move an open chat at any time
+    await program.methods
+      .reassignOracle(oracleKeypair.publicKey)
       .accounts({
         caller: oracleKeypair.publicKey,
-        chat: chatAddress,
+        chat: thirdChat,
       })
       .signers([oracleKeypair])
       .rpc({ skipPreflight: true });
-    console.log("Your transaction signature", txOracle);
+    chat = await program.account.chat.fetch(thirdChat);
+    expect(chat.oracle.toBase58()).to.equal(
+      oracleKeypair.publicKey.toBase58()
+    );
+
+    for (const oracle of [oracleKeypair.publicKey, otherOracle]) {
+      await program.methods
+        .removeOracle(oracle)
+        .accounts({ admin: oracleKeypair.publicKey })
+        .signers([oracleKeypair])
+        .rpc({ skipPreflight: true });
+    }
+  });
+
+  it("Stake, Unstake And Withdraw Bond!", async () => {
+    const minBond = new BN(web3.LAMPORTS_PER_SOL / 10);
+    await program.methods
+      .setBondParams(minBond, new BN(0), new BN(0), new BN(0))
+      .accounts({ admin: oracleKeypair.publicKey })
+      .signers([oracleKeypair])
+      .rpc({ skipPreflight: true });
+
+    await program.methods
+      .stake(minBond)
+      .accounts({ operator: oracleKeypair.publicKey })
+      .signers([oracleKeypair])
+      .rpc({ skipPreflight: true });
 
-    chat = await program.account.chat.fetch(chatAddress);
+    const [configAddress] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("config")],
+      program.programId
+    );
+    let config = await program.account.config.fetch(configAddress);
+    expect(config.oracles.map((o) => o.toBase58())).to.include(
+      oracleKeypair.publicKey.toBase58()
+    );
+
+    // unstaking below the minimum deregisters right away
+    await program.methods
+      .requestUnstake(minBond)
+      .accounts({ operator: oracleKeypair.publicKey })
+      .signers([oracleKeypair])
+      .rpc({ skipPreflight: true });
+    config = await program.account.config.fetch(configAddress);
+    expect(config.oracles.length).to.equal(0);
+
+    await program.methods
+      .withdrawStake()
+      .accounts({ operator: oracleKeypair.publicKey })
+      .signers([oracleKeypair])
+      .rpc({ skipPreflight: true });
+
+    const [bondAddress] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("oracle_bond"), oracleKeypair.publicKey.toBuffer()],
+      program.programId
+    );
+    const bond = await program.account.oracleBond.fetch(bondAddress);
+    expect(bond.staked.toNumber()).to.equal(0);
+    expect(bond.unstakeAmount.toNumber()).to.equal(0);
+  });
+
+  it("Slash A Missed Deadline Once!", async () => {
+    const PAUSE_GET_DEK = 1 << 1;
+    const minBond = new BN(web3.LAMPORTS_PER_SOL / 10);
+    const penalty = new BN(web3.LAMPORTS_PER_SOL / 100);
+    const slowOracle = web3.Keypair.generate();
+    const [bond] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("oracle_bond"), slowOracle.publicKey.toBuffer()],
+      program.programId
+    );
+    const { blockhash, lastValidBlockHeight } =
+      await provider.connection.getLatestBlockhash();
+    await provider.connection.confirmTransaction(
+      {
+        signature: await provider.connection.requestAirdrop(
+          slowOracle.publicKey,
+          web3.LAMPORTS_PER_SOL
+        ),
+        blockhash,
+        lastValidBlockHeight,
+      },
+      "confirmed"
+    );
+
+    // a one-second deadline keeps the test short
+    const bondParams = (deadlineSlash: BN, deadlineSecs: number) =>
+      program.methods
+        .setBondParams(minBond, new BN(0), deadlineSlash, new BN(deadlineSecs))
+        .accounts({ admin: oracleKeypair.publicKey })
+        .signers([oracleKeypair])
+        .rpc({ skipPreflight: true });
+    await bondParams(penalty, 1);
+    await program.methods
+      .stake(minBond)
+      .accounts({ operator: slowOracle.publicKey })
+      .signers([slowOracle])
+      .rpc({ skipPreflight: true });
+
+    const chats = [];
+    for (let i = 0; i < 2; i++) {
+      const { id, address } = await nextChat();
+      await program.methods
+        .createChat(id, cmk, txId, null, MODEL_ID, slowOracle.publicKey, null)
+        .accounts({
+          payer: provider.wallet.publicKey,
+          owner: provider.wallet.publicKey,
+          contextAccount: contextAccount,
+          model: modelAddress,
+        })
+        .rpc({ skipPreflight: true });
+      chats.push(address);
+    }
+    const [missed, revoked] = chats;
+    await program.methods
+      .revokeChatKeys()
+      .accounts({ user: provider.wallet.publicKey, chat: revoked })
+      .rpc({ skipPreflight: true });
+
+    // owner status updates are not a sign of life from the oracle
+    await new Promise((resolve) => setTimeout(resolve, 3000));
+    await program.methods
+      .updateStatus(STATUS_PENDING)
+      .accounts({
+        caller: provider.wallet.publicKey,
+        chat: missed,
+        contextAccount: contextAccount,
+        chatAccess: null,
+      })
+      .rpc({ skipPreflight: true });
+
+    const slash = (chat: web3.PublicKey) =>
+      program.methods
+        .slashMissedDeadline()
+        .accounts({
+          reporter: provider.wallet.publicKey,
+          chat,
+          contextAccount: contextAccount,
+          bond,
+        })
+        .rpc({ skipPreflight: true });
+
+    // the oracle is excused while the owner blocks key access
+    await expectError(() => slash(revoked), "KeysRevoked");
+    await program.methods
+      .pause(PAUSE_GET_DEK)
+      .accounts({ admin: oracleKeypair.publicKey })
+      .signers([oracleKeypair])
+      .rpc({ skipPreflight: true });
+    await expectError(() => slash(missed), "Paused");
+    await program.methods
+      .unpause(PAUSE_GET_DEK)
+      .accounts({ admin: oracleKeypair.publicKey })
+      .signers([oracleKeypair])
+      .rpc({ skipPreflight: true });
+
+    await slash(missed);
+    await expectError(() => slash(missed), "AlreadySlashed");
+    const slashed = await program.account.oracleBond.fetch(bond);
+    expect(slashed.staked.toString()).to.equal(minBond.sub(penalty).toString());
+
+    // below the minimum stake, so the slash also deregistered it
+    const [configAddress] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("config")],
+      program.programId
+    );
+    const config = await program.account.config.fetch(configAddress);
+    expect(config.oracles.map((o) => o.toBase58())).to.not.include(
+      slowOracle.publicKey.toBase58()
+    );
+
+    // free the pending slots again
+    for (const chat of chats) {
+      await program.methods
+        .cancelChat()
+        .accounts({
+          user: provider.wallet.publicKey,
+          chat,
+          contextAccount: contextAccount,
+        })
+        .rpc({ skipPreflight: true });
+    }
+    await bondParams(new BN(0), 0);
+  });
+
+  // disputes are raised against a staked oracle so an upheld one has a bond to slash
+  const STATUS_SUBMITTED = 6;
+  const STATUS_ERROR = 3;
+  const disputedOracle = web3.Keypair.generate();
+  const [disputedBond] = web3.PublicKey.findProgramAddressSync(
+    [Buffer.from("oracle_bond"), disputedOracle.publicKey.toBuffer()],
+    program.programId
+  );
+  const disputedStake = new BN(web3.LAMPORTS_PER_SOL / 10);
+  const disputeSlash = new BN(web3.LAMPORTS_PER_SOL / 100);
+  const disputeParams = (window: number, bond = 10_000, slash = new BN(0)) =>
+    program.methods
+      .setDisputeParams(
+        oracleKeypair.publicKey,
+        new BN(window),
+        new BN(bond),
+        slash
+      )
+      .accounts({ admin: oracleKeypair.publicKey })
+      .signers([oracleKeypair])
+      .rpc({ skipPreflight: true });
+
+  // a chat assigned to the disputed oracle, finalized and waiting out its window
+  const submitDisputedChat = async (resultHash: number) => {
+    const { id, address } = await nextChat();
+    await program.methods
+      .createChat(id, cmk, txId, null, MODEL_ID, disputedOracle.publicKey, null)
+      .accounts({
+        payer: provider.wallet.publicKey,
+        owner: provider.wallet.publicKey,
+        contextAccount: contextAccount,
+        model: modelAddress,
+      })
+      .rpc({ skipPreflight: true });
+    await program.methods
+      .finalizeChat(Array(32).fill(resultHash))
+      .accounts({
+        oracle: disputedOracle.publicKey,
+        chat: address,
+        contextAccount: contextAccount,
+      })
+      .signers([disputedOracle])
+      .rpc({ skipPreflight: true });
+    const chat = await program.account.chat.fetch(address);
+    expect(chat.status).to.equal(STATUS_SUBMITTED);
+    await program.methods
+      .openDispute(Array(32).fill(6))
+      .accounts({
+        challenger: provider.wallet.publicKey,
+        chat: address,
+      })
+      .rpc({ skipPreflight: true });
+    return address;
+  };
+
+  const resolve = (chat: web3.PublicKey, upheld: boolean) =>
+    program.methods
+      .resolveDispute(upheld)
+      .accounts({
+        arbiter: oracleKeypair.publicKey,
+        chat,
+        contextAccount: contextAccount,
+        challenger: provider.wallet.publicKey,
+        oracle: disputedOracle.publicKey,
+        bond: disputedBond,
+        submissions: null,
+      })
+      .signers([oracleKeypair])
+      .rpc({ skipPreflight: true });
+
+  it("Rejected Dispute Pays The Oracle And The Result Settles!", async () => {
+    const { blockhash, lastValidBlockHeight } =
+      await provider.connection.getLatestBlockhash();
+    await provider.connection.confirmTransaction(
+      {
+        signature: await provider.connection.requestAirdrop(
+          disputedOracle.publicKey,
+          web3.LAMPORTS_PER_SOL
+        ),
+        blockhash,
+        lastValidBlockHeight,
+      },
+      "confirmed"
+    );
+    await program.methods
+      .setBondParams(disputedStake, new BN(0), new BN(0), new BN(0))
+      .accounts({ admin: oracleKeypair.publicKey })
+      .signers([oracleKeypair])
+      .rpc({ skipPreflight: true });
+    await program.methods
+      .stake(disputedStake)
+      .accounts({ operator: disputedOracle.publicKey })
+      .signers([disputedOracle])
+      .rpc({ skipPreflight: true });
+
+    // a challenge window without a bond is rejected
+    await expectError(() => disputeParams(3600, 0), "InvalidDisputeParams");
+
+    await disputeParams(5, 10_000, disputeSlash);
+    const disputedChat = await submitDisputedChat(4);
+
+    const oracleBefore = await provider.connection.getBalance(
+      disputedOracle.publicKey
+    );
+    await resolve(disputedChat, false);
+    const oracleAfter = await provider.connection.getBalance(
+      disputedOracle.publicKey
+    );
+    expect(oracleAfter - oracleBefore).to.equal(10_000);
+    let bond = await program.account.oracleBond.fetch(disputedBond);
+    expect(bond.staked.toString()).to.equal(disputedStake.toString());
+
+    const settle = () =>
+      program.methods
+        .settleResult()
+        .accounts({
+          caller: provider.wallet.publicKey,
+          chat: disputedChat,
+          contextAccount: contextAccount,
+        })
+        .rpc({ skipPreflight: true });
+
+    // the result stands, but only settles once the window has closed
+    let chat = await program.account.chat.fetch(disputedChat);
+    expect(chat.status).to.equal(STATUS_SUBMITTED);
+    expect(chat.disputed).to.equal(false);
+    await expectError(settle, "ChallengeWindowOpen");
+    await new Promise((resolve) => setTimeout(resolve, 6000));
+    await settle();
+    chat = await program.account.chat.fetch(disputedChat);
+    expect(chat.status).to.equal(STATUS_DONE);
+  });
+
+  it("Upheld Dispute Overturns A Result And Slashes The Oracle!", async () => {
+    await disputeParams(3600, 10_000, disputeSlash);
+    const disputedChat = await submitDisputedChat(5);
+
+    await resolve(disputedChat, true);
+    const chat = await program.account.chat.fetch(disputedChat);
+    expect(chat.status).to.equal(STATUS_ERROR);
+    expect(chat.errorCode).to.deep.equal({ resultDisputed: {} });
+
+    const bond = await program.account.oracleBond.fetch(disputedBond);
+    expect(bond.staked.toString()).to.equal(disputedStake.sub(disputeSlash).toString());
+    expect(bond.slashedTotal.toString()).to.equal(disputeSlash.toString());
+
+    // below the minimum stake, so the slash also deregistered it
+    const [configAddress] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("config")],
+      program.programId
+    );
+    const config = await program.account.config.fetch(configAddress);
+    expect(config.oracles.map((o) => o.toBase58())).to.not.include(
+      disputedOracle.publicKey.toBase58()
+    );
+
+    await disputeParams(0);
+    await program.methods
+      .setBondParams(new BN(0), new BN(0), new BN(0), new BN(0))
+      .accounts({ admin: oracleKeypair.publicKey })
+      .signers([oracleKeypair])
+      .rpc({ skipPreflight: true });
+  });
+
+  it("Abandon A Result Whose Callback Reverts!", async () => {
+    const { id: stuckChatId, address: stuckChat } = await nextChat();
+
+    // the system program rejects the unknown instruction, so every settle reverts
+    const revertingCallback = {
+      programId: web3.SystemProgram.programId,
+      discriminator: Array(8).fill(255),
+      accounts: [],
+    };
+    await disputeParams(1);
+    await program.methods
+      .createChat(stuckChatId, cmk, txId, revertingCallback, MODEL_ID, null, null)
+      .accounts({
+        payer: provider.wallet.publicKey,
+        owner: provider.wallet.publicKey,
+        contextAccount: contextAccount,
+        model: modelAddress,
+      })
+      .rpc({ skipPreflight: true });
+    await program.methods
+      .finalizeChat(Array(32).fill(8))
+      .accounts({
+        oracle: oracleKeypair.publicKey,
+        chat: stuckChat,
+        contextAccount: contextAccount,
+      })
+      .signers([oracleKeypair])
+      .rpc({ skipPreflight: true });
+    let chat = await program.account.chat.fetch(stuckChat);
+    expect(chat.status).to.equal(STATUS_SUBMITTED);
+    await new Promise((resolve) => setTimeout(resolve, 3000));
+
+    await expectError(
+      () =>
+        program.methods
+          .settleResult()
+          .accounts({
+            caller: provider.wallet.publicKey,
+            chat: stuckChat,
+            contextAccount: contextAccount,
+          })
+          .remainingAccounts([
+            {
+              pubkey: web3.SystemProgram.programId,
+              isSigner: false,
+              isWritable: false,
+            },
+          ])
+          .rpc({ skipPreflight: true }),
+      ""
+    );
+
+    // the owner has to wait a further oracle deadline
+    await expectError(
+      () =>
+        program.methods
+          .abandonResult()
+          .accounts({
+            caller: provider.wallet.publicKey,
+            chat: stuckChat,
+            contextAccount: contextAccount,
+          })
+          .rpc({ skipPreflight: true }),
+      "ChallengeWindowOpen"
+    );
+
+    const pendingBefore = (await program.account.contextAccount.fetch(contextAccount))
+      .pendingChats;
+    await program.methods
+      .abandonResult()
+      .accounts({
+        caller: oracleKeypair.publicKey,
+        chat: stuckChat,
+        contextAccount: contextAccount,
+      })
+      .signers([oracleKeypair])
+      .rpc({ skipPreflight: true });
+    chat = await program.account.chat.fetch(stuckChat);
+    expect(chat.status).to.equal(STATUS_ERROR);
+    expect(chat.errorCode).to.deep.equal({ settlementFailed: {} });
+    const pendingAfter = (await program.account.contextAccount.fetch(contextAccount))
+      .pendingChats;
+    expect(pendingAfter).to.equal(pendingBefore - 1);
+
+    await disputeParams(0);
+  });
+
+  it("Two Of Three Oracles Agree On A Result!", async () => {
+    const { id: consensusChatId, address: consensusChat } = await nextChat();
+    const [submissionsAddress] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("submissions"), consensusChat.toBuffer()],
+      program.programId
+    );
+    const oracles = [
+      oracleKeypair,
+      web3.Keypair.generate(),
+      web3.Keypair.generate(),
+    ];
+    const agreed = Array(32).fill(1);
+    const dissent = Array(32).fill(2);
+
+    for (const oracle of oracles) {
+      await program.methods
+        .addOracle(oracle.publicKey)
+        .accounts({ admin: oracleKeypair.publicKey })
+        .signers([oracleKeypair])
+        .rpc({ skipPreflight: true });
+    }
+
+    await program.methods
+      .createChat(consensusChatId, cmk, txId, null, MODEL_ID, null, null)
+      .accounts({
+        payer: provider.wallet.publicKey,
+        owner: provider.wallet.publicKey,
+        contextAccount: contextAccount,
+        model: modelAddress,
+      })
+      .rpc({ skipPreflight: true });
+
+    // only three oracles are registered
+    let failed = false;
+    try {
+      await program.methods
+        .setConsensus(4)
+        .accounts({
+          user: provider.wallet.publicKey,
+          chat: consensusChat,
+        })
+        .rpc({ skipPreflight: true });
+    } catch (e) {
+      failed = true;
+      expect(String(e)).to.contain("InvalidConfirmations");
+    }
+    expect(failed).to.equal(true);
+
+    await program.methods
+      .setConsensus(2)
+      .accounts({
+        user: provider.wallet.publicKey,
+        chat: consensusChat,
+      })
+      .rpc({ skipPreflight: true });
+
+    for (const [oracle, hash] of [
+      [oracles[1], agreed],
+      [oracles[2], dissent],
+      [oracles[0], agreed],
+    ] as [web3.Keypair, number[]][]) {
+      await program.methods
+        .submitResult(hash)
+        .accounts({
+          oracle: oracle.publicKey,
+          chat: consensusChat,
+          contextAccount: contextAccount,
+        })
+        .signers([oracle])
+        .rpc({ skipPreflight: true });
+    }
+
+    const chat = await program.account.chat.fetch(consensusChat);
     expect(chat.status).to.equal(STATUS_DONE);
+    expect(chat.resultHash).to.deep.equal(agreed);
+
+    // the dissenting submission stays on record
+    const submissions = await program.account.resultSubmissions.fetch(
+      submissionsAddress
+    );
+    expect(submissions.agreedHash).to.deep.equal(agreed);
+    expect(submissions.submissions[1].resultHash).to.deep.equal(dissent);
+
+    for (const oracle of oracles) {
+      await program.methods
+        .removeOracle(oracle.publicKey)
+        .accounts({ admin: oracleKeypair.publicKey })
+        .signers([oracleKeypair])
+        .rpc({ skipPreflight: true });
+    }
+  });
+
+  it("Derive The Chat Key From The Context Master Key!", async () => {
+    const masterKey = web3.Keypair.generate().publicKey;
+    const setMasterKey = (key: web3.PublicKey | null) =>
+      program.methods
+        .setContextMasterKey(key)
+        .accounts({
+          owner: provider.wallet.publicKey,
+          contextAccount: contextAccount,
+        })
+        .rpc({ skipPreflight: true });
+    const createWithoutCmk = (id: BN) =>
+      program.methods
+        .createChat(id, null, txId, null, MODEL_ID, null, null)
+        .accounts({
+          payer: provider.wallet.publicKey,
+          owner: provider.wallet.publicKey,
+          contextAccount: contextAccount,
+          model: modelAddress,
+        })
+        .rpc({ skipPreflight: true });
+
+    await setMasterKey(masterKey);
+    const { id, address } = await nextChat();
+    await createWithoutCmk(id);
+
+    // cmk = HKDF-SHA256(master key, no salt, info = "chat:" || chat_id LE)
+    const info = Buffer.concat([
+      Buffer.from("chat:"),
+      id.toArrayLike(Buffer, "le", 8),
+    ]);
+    const expected = Buffer.from(
+      hkdfSync("sha256", masterKey.toBuffer(), Buffer.alloc(0), info, 32)
+    );
+    const chat = await program.account.chat.fetch(address);
+    expect(chat.cmk.toBuffer().equals(expected)).to.equal(true);
+
+    // without a master key the caller has to bring its own cmk
+    await setMasterKey(null);
+    const { id: nextId } = await nextChat();
+    await expectError(() => createWithoutCmk(nextId), "MissingMasterKey");
+
+    await program.methods
+      .cancelChat()
+      .accounts({
+        user: provider.wallet.publicKey,
+        chat: address,
+        contextAccount: contextAccount,
+      })
+      .rpc({ skipPreflight: true });
+  });
+
+  it("Pay For Chats In Tokens!", async () => {
+    const PRICED_MODEL_ID = 1;
+    const [pricedModel] = web3.PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("model"),
+        new BN(PRICED_MODEL_ID).toArrayLike(Buffer, "le", 4),
+      ],
+      program.programId
+    );
+    if (!(await program.account.model.fetchNullable(pricedModel))) {
+      await program.methods
+        .registerModel(PRICED_MODEL_ID, "priced-model", new BN(5), 8192)
+        .accounts({ admin: oracleKeypair.publicKey })
+        .signers([oracleKeypair])
+        .rpc({ skipPreflight: true });
+    }
+
+    for (const tokenProgram of [TOKEN_PROGRAM_ID, TOKEN_2022_PROGRAM_ID]) {
+      const mint = await createMint(
+        provider.connection,
+        oracleKeypair,
+        oracleKeypair.publicKey,
+        null,
+        6,
+        undefined,
+        undefined,
+        tokenProgram
+      );
+      const source = await getOrCreateAssociatedTokenAccount(
+        provider.connection,
+        oracleKeypair,
+        mint,
+        provider.wallet.publicKey,
+        false,
+        undefined,
+        undefined,
+        tokenProgram
+      );
+      await mintTo(
+        provider.connection,
+        oracleKeypair,
+        mint,
+        source.address,
+        oracleKeypair,
+        1_000_000,
+        [],
+        undefined,
+        tokenProgram
+      );
+
+      // one credit costs 0.01 of the 6-decimal mint
+      await program.methods
+        .addAllowedMint(mint, new BN(10_000))
+        .accounts({ admin: oracleKeypair.publicKey })
+        .signers([oracleKeypair])
+        .rpc({ skipPreflight: true });
+      await program.methods
+        .depositTokens(new BN(100_000))
+        .accounts({
+          owner: provider.wallet.publicKey,
+          mint,
+          source: source.address,
+          tokenProgram,
+        })
+        .rpc({ skipPreflight: true });
+
+      // no credits are needed or held for a token-paid chat
+      const { id, address } = await nextChat();
+      const before = await program.account.contextAccount.fetch(contextAccount);
+      await program.methods
+        .createChat(id, cmk, txId, null, PRICED_MODEL_ID, null, mint)
+        .accounts({
+          payer: provider.wallet.publicKey,
+          owner: provider.wallet.publicKey,
+          contextAccount: contextAccount,
+          model: pricedModel,
+        })
+        .rpc({ skipPreflight: true });
+      const context = await program.account.contextAccount.fetch(contextAccount);
+      expect(context.reservedCredits.toString()).to.equal(
+        before.reservedCredits.toString()
+      );
+      let chat = await program.account.chat.fetch(address);
+      expect(chat.reservedCredits.toNumber()).to.equal(0);
+      expect(chat.tokensDue.toNumber()).to.equal(50_000);
+
+      const charge = () =>
+        program.methods
+          .chargeTokens()
+          .accounts({
+            oracle: oracleKeypair.publicKey,
+            chat: address,
+            mint,
+            tokenProgram,
+          })
+          .signers([oracleKeypair])
+          .rpc({ skipPreflight: true });
+
+      // only a DONE chat is charged
+      await expectError(charge, "ChatNotDone");
+      await program.methods
+        .finalizeChat(Array(32).fill(9))
+        .accounts({
+          oracle: oracleKeypair.publicKey,
+          chat: address,
+          contextAccount: contextAccount,
+        })
+        .signers([oracleKeypair])
+        .rpc({ skipPreflight: true });
+
+      await charge();
+      await expectError(charge, "AlreadyCharged");
+      chat = await program.account.chat.fetch(address);
+      expect(chat.tokensCharged).to.equal(true);
+
+      const [vault] = web3.PublicKey.findProgramAddressSync(
+        [
+          Buffer.from("token_vault"),
+          provider.wallet.publicKey.toBuffer(),
+          mint.toBuffer(),
+        ],
+        program.programId
+      );
+      const [treasury] = web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("token_treasury"), mint.toBuffer()],
+        program.programId
+      );
+      const balance = async (account: web3.PublicKey) =>
+        Number(
+          (
+            await getAccount(
+              provider.connection,
+              account,
+              undefined,
+              tokenProgram
+            )
+          ).amount
+        );
+      expect(await balance(vault)).to.equal(50_000);
+      expect(await balance(treasury)).to.equal(50_000);
+    }
   });
 
   // it("Delegate Chat!", async () => {